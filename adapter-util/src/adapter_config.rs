@@ -21,6 +21,12 @@ pub struct PlugIns {
 
     #[serde(rename = "zenoh")]
     pub zenoh: Option<ZenohPlugin>,
+
+    #[serde(rename = "nats")]
+    pub nats: Option<NatsPlugin>,
+
+    #[serde(rename = "mqtt")]
+    pub mqtt: Option<MqttPlugin>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -51,6 +57,9 @@ pub struct ZenohPlugin {
     #[serde(rename = "enabled")]
     pub enabled: bool,
 
+    #[serde(rename = "encoding", default)]
+    pub encoding: Encoding,
+
     #[serde(rename = "publish")]
     pub publish: Option<Vec<ZenohTopic>>,
 
@@ -58,6 +67,71 @@ pub struct ZenohPlugin {
     pub subscribe: Option<Vec<ZenohTopic>>,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NatsPlugin {
+    #[serde(rename = "enabled")]
+    pub enabled: bool,
+
+    #[serde(rename = "connect-url")]
+    pub connect_url: Option<String>,
+
+    #[serde(rename = "encoding", default)]
+    pub encoding: Encoding,
+
+    #[serde(rename = "security")]
+    pub security: Option<SecuritySettings>,
+
+    #[serde(rename = "publish")]
+    pub publish: Option<Vec<ZenohTopic>>,
+
+    #[serde(rename = "subscribe")]
+    pub subscribe: Option<Vec<ZenohTopic>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MqttPlugin {
+    #[serde(rename = "enabled")]
+    pub enabled: bool,
+
+    #[serde(rename = "host")]
+    pub host: String,
+
+    #[serde(rename = "port")]
+    pub port: Option<u16>,
+
+    #[serde(rename = "client-id")]
+    pub client_id: Option<String>,
+
+    #[serde(rename = "keep-alive-seconds")]
+    pub keep_alive_seconds: Option<u64>,
+
+    /// MQTT quality of service level: 0, 1 or 2
+    #[serde(rename = "qos")]
+    pub qos: Option<u8>,
+
+    #[serde(rename = "encoding", default)]
+    pub encoding: Encoding,
+
+    #[serde(rename = "security")]
+    pub security: Option<SecuritySettings>,
+
+    #[serde(rename = "publish")]
+    pub publish: Option<Vec<ZenohTopic>>,
+
+    #[serde(rename = "subscribe")]
+    pub subscribe: Option<Vec<ZenohTopic>>,
+}
+
+/// Encoding of the OpenFMB messages on a bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum Encoding {
+    #[default]
+    #[serde(rename = "protobuf")]
+    Protobuf,
+    #[serde(rename = "json")]
+    Json,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SecuritySettings {
     #[serde(rename = "security-type")]
@@ -109,6 +109,48 @@ pub fn get_current_timestamp() -> Timestamp {
     timestamp_from_datetime(Utc::now())
 }
 
+/// Current time stamped with the quality of the host clock
+pub fn get_host_timestamp() -> Timestamp {
+    let mut timestamp = get_current_timestamp();
+    timestamp.tq = Some(host_time_quality());
+    timestamp
+}
+
+/// Time quality of the host clock.  The clock is reported as not synchronized unless the kernel
+/// says it is disciplined (e.g. by NTP or PTP), in which case the accuracy is derived from the
+/// estimated error as the number of significant bits of a second fraction.
+#[cfg(target_os = "linux")]
+pub fn host_time_quality() -> TimeQuality {
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    let state = unsafe { libc::adjtimex(&mut timex) };
+
+    if state < 0 || state == libc::TIME_ERROR || timex.status & libc::STA_UNSYNC != 0 {
+        return unsynchronized_time_quality();
+    }
+
+    // Estimated error is in microseconds
+    let error_us = (timex.esterror as f64).max(1.0);
+    TimeQuality {
+        leap_seconds_known: true,
+        time_accuracy: ((1e6 / error_us).log2().floor() as i32).clamp(0, 24),
+        ..Default::default()
+    }
+}
+
+/// Time quality of the host clock.  Synchronization cannot be checked on this platform.
+#[cfg(not(target_os = "linux"))]
+pub fn host_time_quality() -> TimeQuality {
+    unsynchronized_time_quality()
+}
+
+fn unsynchronized_time_quality() -> TimeQuality {
+    TimeQuality {
+        clock_not_synchronized: true,
+        time_accuracy: 31, // unspecified
+        ..Default::default()
+    }
+}
+
 pub fn fraction_to_ms(fraction: u32) -> u32 {
     (fraction as f64 / 1000f64 * ((2 ^ 32) as f64)) as u32
 }
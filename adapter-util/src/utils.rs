@@ -79,3 +79,7 @@ pub fn parse_bit_string(bit_string: &str) -> Option<i32> {
         Err(e) => None,
     }
 }
+
+pub fn to_bit_string(value: i32) -> String {
+    format!("{:b}", value)
+}
@@ -0,0 +1,301 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use adapter_util::*;
+use async_trait::async_trait;
+use core::str::FromStr;
+use futures::stream::{self, BoxStream, StreamExt};
+use log::{debug, error};
+use openfmb::messages::switchmodule::{
+    SwitchDiscreteControlProfile, SwitchReadingProfile, SwitchStatusProfile,
+};
+use openfmb::prelude::*;
+use openfmb_messages_ext::OpenFMBExt;
+use prost::Message;
+use serde::de::DeserializeOwned;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[cfg(feature = "zenoh")]
+pub mod zenoh;
+#[cfg(feature = "zenoh")]
+pub use self::zenoh::*;
+
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "nats")]
+pub use self::nats::*;
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "mqtt")]
+pub use self::mqtt::*;
+
+/// Profiles published by the processors
+#[derive(Clone, Debug)]
+pub enum ProfileMessage {
+    Reading(SwitchReadingProfile),
+    Status(SwitchStatusProfile),
+}
+
+impl ProfileMessage {
+    pub fn device_mrid(&self) -> Option<Uuid> {
+        match self {
+            ProfileMessage::Reading(m) => m.device_mrid().ok(),
+            ProfileMessage::Status(m) => m.device_mrid().ok(),
+        }
+    }
+
+    /// The message in `encoding`
+    pub fn encode(&self, encoding: Encoding) -> Result<Vec<u8>, BusError> {
+        let encoded = match (self, encoding) {
+            (ProfileMessage::Reading(m), Encoding::Protobuf) => Ok(m.encode_to_vec()),
+            (ProfileMessage::Status(m), Encoding::Protobuf) => Ok(m.encode_to_vec()),
+            (ProfileMessage::Reading(m), Encoding::Json) => serde_json::to_vec(m),
+            (ProfileMessage::Status(m), Encoding::Json) => serde_json::to_vec(m),
+        };
+        encoded.map_err(|e| BusError::Publish(e.to_string()))
+    }
+}
+
+/// Decode a message received in `encoding`
+pub fn decode<M: Message + Default + DeserializeOwned>(
+    encoding: Encoding,
+    payload: &[u8],
+) -> Result<M, String> {
+    match encoding {
+        Encoding::Protobuf => M::decode(payload).map_err(|e| e.to_string()),
+        Encoding::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+    }
+}
+
+impl From<SwitchReadingProfile> for ProfileMessage {
+    fn from(message: SwitchReadingProfile) -> Self {
+        ProfileMessage::Reading(message)
+    }
+}
+
+impl From<SwitchStatusProfile> for ProfileMessage {
+    fn from(message: SwitchStatusProfile) -> Self {
+        ProfileMessage::Status(message)
+    }
+}
+
+/// Control profiles received from a bus
+pub type ControlStream = BoxStream<'static, SwitchDiscreteControlProfile>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BusError {
+    Connect(String),
+    Publish(String),
+    Subscribe(String),
+}
+
+impl Display for BusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            BusError::Connect(e) => write!(f, "Failed to connect: {}", e),
+            BusError::Publish(e) => write!(f, "Failed to publish message: {}", e),
+            BusError::Subscribe(e) => write!(f, "Failed to subscribe: {}", e),
+        }
+    }
+}
+
+/// A message bus transport.  Each transport is enabled by its section in `PlugIns` and built
+/// behind the cargo feature of the same name.
+#[async_trait]
+pub trait MessageBus: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Whether the publish topics of this bus include `profile` for `mrid`
+    fn is_publishing(&self, profile: &str, mrid: &str) -> bool;
+
+    /// Whether the subscribe topics of this bus include `profile` for `mrid`
+    fn is_subscribing(&self, profile: &str, mrid: &str) -> bool;
+
+    async fn publish(
+        &self,
+        profile: &str,
+        mrid: &Uuid,
+        message: ProfileMessage,
+    ) -> Result<(), BusError>;
+
+    async fn subscribe(&self, profile: &str, mrid: &Uuid) -> Result<ControlStream, BusError>;
+}
+
+/// Check a plugin's topic list for `profile` and `mrid`; a `*` subject matches every mRID
+pub fn is_topic_enabled(topics: &Option<Vec<ZenohTopic>>, profile: &str, mrid: &str) -> bool {
+    topics
+        .iter()
+        .flatten()
+        .any(|t| t.profile == profile && (t.subject == "*" || t.subject == mrid))
+}
+
+/// Create profile topic
+pub fn topic(typ: &str, mrid: &Uuid) -> ProfileTopic {
+    ProfileTopic::new(
+        Module::SwitchModule,
+        openfmb::topic::Profile::from_str(typ).unwrap(),
+        mrid.clone(),
+    )
+}
+
+/// The message buses enabled in the adapter configuration.  Profiles are published to, and
+/// controls received from, every bus whose topic lists include them.
+#[derive(Clone, Default)]
+pub struct Bus {
+    buses: Vec<Arc<dyn MessageBus>>,
+}
+
+impl Bus {
+    pub fn new(buses: Vec<Arc<dyn MessageBus>>) -> Bus {
+        Bus { buses }
+    }
+
+    /// Connect the buses enabled in `PlugIns`
+    #[allow(unused_variables)]
+    pub async fn from_config(adapter_config: &AdapterConfig) -> Result<Bus, BusError> {
+        #[allow(unused_mut)]
+        let mut buses: Vec<Arc<dyn MessageBus>> = vec![];
+
+        #[cfg(feature = "zenoh")]
+        if let Some(bus) = ZenohMessageBus::from_config(adapter_config) {
+            buses.push(Arc::new(bus));
+        }
+
+        #[cfg(feature = "nats")]
+        if let Some(bus) = NatsMessageBus::from_config(adapter_config).await? {
+            buses.push(Arc::new(bus));
+        }
+
+        #[cfg(feature = "mqtt")]
+        if let Some(bus) = MqttMessageBus::from_config(adapter_config)? {
+            buses.push(Arc::new(bus));
+        }
+
+        Ok(Bus { buses })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buses.is_empty()
+    }
+
+    pub fn is_publishing(&self, profile: &str, mrid: &str) -> bool {
+        self.buses.iter().any(|b| b.is_publishing(profile, mrid))
+    }
+
+    pub fn is_subscribing(&self, profile: &str, mrid: &str) -> bool {
+        self.buses.iter().any(|b| b.is_subscribing(profile, mrid))
+    }
+
+    /// Publish to every bus with `profile` in its publish topics
+    pub async fn publish<M: Into<ProfileMessage>>(&self, profile: &str, message: M) {
+        let message = message.into();
+        let mrid = match message.device_mrid() {
+            Some(mrid) => mrid,
+            None => {
+                error!("Missing device mRID in {} message", profile);
+                return;
+            }
+        };
+
+        for bus in &self.buses {
+            if !bus.is_publishing(profile, &mrid.to_string()) {
+                continue;
+            }
+            match bus.publish(profile, &mrid, message.clone()).await {
+                Ok(_) => debug!("{} published to {}!", profile, bus.name()),
+                Err(e) => error!("{}::{}", bus.name(), e),
+            }
+        }
+    }
+
+    /// Control messages for `mrid` from every bus with `profile` in its subscribe topics
+    pub async fn subscribe(&self, profile: &str, mrid: &Uuid) -> ControlStream {
+        let mut subscriptions = vec![];
+        for bus in &self.buses {
+            if !bus.is_subscribing(profile, &mrid.to_string()) {
+                continue;
+            }
+            match bus.subscribe(profile, mrid).await {
+                Ok(subscription) => {
+                    debug!("Subscribed to {}.{} on {}", profile, mrid, bus.name());
+                    subscriptions.push(subscription);
+                }
+                Err(e) => error!("{}::{}", bus.name(), e),
+            }
+        }
+        stream::select_all(subscriptions).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_profiles;
+    use std::fmt::Debug;
+
+    const TEMPLATE: &str = include_str!("../../../template.yaml");
+
+    /// Encode `message` in both encodings and check that each decodes to `message`.  Buses encode
+    /// with `ProfileMessage::encode`, except Zenoh which uses the openfmb encoders.
+    fn assert_round_trip<M>(message: &M, encode: impl Fn(Encoding) -> Vec<u8>)
+    where
+        M: Message + Default + DeserializeOwned + PartialEq + Debug,
+    {
+        for encoding in [Encoding::Protobuf, Encoding::Json] {
+            assert_eq!(
+                decode::<M>(encoding, &encode(encoding)).as_ref(),
+                Ok(message)
+            );
+        }
+    }
+
+    #[test]
+    fn encodings_decode_the_same() {
+        let mut names = vec![];
+        for profile in read_profiles(TEMPLATE) {
+            match (Builder {}).build(&profile.name, &profile.content) {
+                VisitorType::SwitchReading(_, mut p, mut visitor) => {
+                    visitor.visit(&mut p);
+                    let message = ProfileMessage::from(p.clone());
+                    assert!(message.device_mrid().is_some());
+                    assert_round_trip(&p, |encoding| message.encode(encoding).unwrap());
+                    #[cfg(feature = "zenoh")]
+                    assert_round_trip(&p, |encoding| {
+                        super::zenoh::zenoh_payload(encoding, &message)
+                    });
+                }
+                VisitorType::SwitchStatus(_, mut p, mut visitor) => {
+                    visitor.visit(&mut p);
+                    let message = ProfileMessage::from(p.clone());
+                    assert!(message.device_mrid().is_some());
+                    assert_round_trip(&p, |encoding| message.encode(encoding).unwrap());
+                    #[cfg(feature = "zenoh")]
+                    assert_round_trip(&p, |encoding| {
+                        super::zenoh::zenoh_payload(encoding, &message)
+                    });
+                }
+                VisitorType::SwitchDiscreteControl(_, mut p, mut visitor) => {
+                    visitor.visit(&mut p);
+                    assert_round_trip(&p, |encoding| match encoding {
+                        Encoding::Protobuf => p.encode_to_vec(),
+                        Encoding::Json => serde_json::to_vec(&p).unwrap(),
+                    });
+                }
+            }
+            names.push(profile.name);
+        }
+        assert_eq!(
+            names,
+            [
+                "SwitchDiscreteControlProfile",
+                "SwitchReadingProfile",
+                "SwitchStatusProfile"
+            ]
+        );
+    }
+}
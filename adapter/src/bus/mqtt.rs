@@ -0,0 +1,285 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::*;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::future::ready;
+use log::{info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, Transport};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_TLS_PORT: u16 = 8883;
+const DEFAULT_CLIENT_ID: &str = "udp-adapter";
+const DEFAULT_QOS: u8 = 1;
+const REQUEST_CAPACITY: usize = 100;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// MQTT topic of a profile
+pub fn topic_name(profile: &str, mrid: &Uuid) -> String {
+    format!("openfmb/switchmodule/{}/{}", profile, mrid)
+}
+
+fn read(file: &Option<String>, name: &str) -> Result<Vec<u8>, BusError> {
+    let file = file
+        .as_ref()
+        .ok_or_else(|| BusError::Connect(format!("MQTT security requires {}", name)))?;
+    std::fs::read(file).map_err(|e| BusError::Connect(format!("{}: {}", file, e)))
+}
+
+/// Transport for the `security` section of the `mqtt` plugin; `None` is plain TCP
+fn transport(security: &SecuritySettings) -> Result<Option<Transport>, BusError> {
+    let tls = || match &security.ca_trusted_cert_file {
+        Some(_) => Ok(Transport::tls(
+            read(&security.ca_trusted_cert_file, "ca-trusted-cert-file")?,
+            None,
+            None,
+        )),
+        None => Ok(Transport::tls_with_default_config()),
+    };
+
+    match security.security_type.as_str() {
+        "none" => Ok(None),
+        "tls_server_auth" => tls().map(Some),
+        "tls_client_auth" => Ok(Some(Transport::tls(
+            read(&security.ca_trusted_cert_file, "ca-trusted-cert-file")?,
+            Some((
+                read(&security.client_cert_chain_file, "client-cert-chain-file")?,
+                read(&security.client_private_key_file, "client-private-key-file")?,
+            )),
+            None,
+        ))),
+        "user_password" => match &security.ca_trusted_cert_file {
+            Some(_) => tls().map(Some),
+            None => Ok(None),
+        },
+        other => Err(BusError::Connect(format!(
+            "Unsupported MQTT security type: {}",
+            other
+        ))),
+    }
+}
+
+/// Client options for the `mqtt` plugin section
+pub fn mqtt_options(plugin: &MqttPlugin) -> Result<MqttOptions, BusError> {
+    let transport = match &plugin.security {
+        Some(security) => transport(security)?,
+        None => None,
+    };
+    let port = plugin.port.unwrap_or(match transport {
+        Some(_) => DEFAULT_TLS_PORT,
+        None => DEFAULT_PORT,
+    });
+
+    let mut options = MqttOptions::new(
+        plugin
+            .client_id
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CLIENT_ID.to_string()),
+        plugin.host.clone(),
+        port,
+    );
+    if let Some(seconds) = plugin.keep_alive_seconds {
+        options.set_keep_alive(Duration::from_secs(seconds));
+    }
+    if let Some(transport) = transport {
+        options.set_transport(transport);
+    }
+    if let Some(security) = &plugin.security {
+        if security.security_type == "user_password" {
+            match (&security.user_name, &security.password) {
+                (Some(user), Some(password)) => {
+                    options.set_credentials(user.clone(), password.clone());
+                }
+                _ => {
+                    return Err(BusError::Connect(
+                        "MQTT security requires username and password".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// Quality of service of the `mqtt` plugin section
+pub fn qos(plugin: &MqttPlugin) -> Result<QoS, BusError> {
+    let level = plugin.qos.unwrap_or(DEFAULT_QOS);
+    rumqttc::qos(level).map_err(|_| BusError::Connect(format!("Invalid MQTT QoS: {}", level)))
+}
+
+/// Topics subscribed to, with the senders of their subscriptions
+type Subscriptions = Arc<Mutex<Vec<(String, UnboundedSender<Vec<u8>>)>>>;
+
+/// MQTT client configured by the `mqtt` plugin section
+#[derive(Clone)]
+pub struct MqttMessageBus {
+    client: AsyncClient,
+    plugin: MqttPlugin,
+    qos: QoS,
+    subscriptions: Subscriptions,
+}
+
+impl MqttMessageBus {
+    /// Create the client and spawn its event loop, which must run on a tokio runtime
+    pub fn connect(plugin: MqttPlugin) -> Result<MqttMessageBus, BusError> {
+        let qos = qos(&plugin)?;
+        let (client, eventloop) = AsyncClient::new(mqtt_options(&plugin)?, REQUEST_CAPACITY);
+        let subscriptions: Subscriptions = Arc::new(Mutex::new(vec![]));
+
+        tokio::spawn(MqttMessageBus::run(
+            eventloop,
+            client.clone(),
+            qos,
+            subscriptions.clone(),
+        ));
+
+        Ok(MqttMessageBus {
+            client,
+            plugin,
+            qos,
+            subscriptions,
+        })
+    }
+
+    /// Connect to the broker if MQTT is enabled
+    pub fn from_config(adapter_config: &AdapterConfig) -> Result<Option<MqttMessageBus>, BusError> {
+        match adapter_config
+            .plugins
+            .as_ref()
+            .and_then(|p| p.mqtt.as_ref())
+        {
+            Some(plugin) if plugin.enabled => MqttMessageBus::connect(plugin.clone()).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Drive the connection, reconnecting after errors, and hand incoming messages to the
+    /// subscriptions of their topic
+    async fn run(
+        mut eventloop: EventLoop,
+        client: AsyncClient,
+        qos: QoS,
+        subscriptions: Subscriptions,
+    ) {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker");
+                    // Sessions are clean, so subscriptions are renewed on every connection
+                    for (topic, _) in subscriptions.lock().unwrap().iter() {
+                        if let Err(e) = client.try_subscribe(topic.clone(), qos) {
+                            error!("MQTT::Failed to subscribe to {}: {}", topic, e);
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    subscriptions.lock().unwrap().retain(|(topic, tx)| {
+                        *topic != publish.topic
+                            || tx.unbounded_send(publish.payload.to_vec()).is_ok()
+                    });
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("MQTT::{}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBus for MqttMessageBus {
+    fn name(&self) -> &'static str {
+        "MQTT"
+    }
+
+    fn is_publishing(&self, profile: &str, mrid: &str) -> bool {
+        is_topic_enabled(&self.plugin.publish, profile, mrid)
+    }
+
+    fn is_subscribing(&self, profile: &str, mrid: &str) -> bool {
+        is_topic_enabled(&self.plugin.subscribe, profile, mrid)
+    }
+
+    async fn publish(
+        &self,
+        profile: &str,
+        mrid: &Uuid,
+        message: ProfileMessage,
+    ) -> Result<(), BusError> {
+        let payload = message.encode(self.plugin.encoding)?;
+        self.client
+            .publish(topic_name(profile, mrid), self.qos, false, payload)
+            .await
+            .map_err(|e| BusError::Publish(e.to_string()))
+    }
+
+    async fn subscribe(&self, profile: &str, mrid: &Uuid) -> Result<ControlStream, BusError> {
+        let topic = topic_name(profile, mrid);
+        let (tx, rx) = unbounded();
+        self.subscriptions.lock().unwrap().push((topic.clone(), tx));
+        self.client
+            .subscribe(topic.clone(), self.qos)
+            .await
+            .map_err(|e| BusError::Subscribe(e.to_string()))?;
+
+        let encoding = self.plugin.encoding;
+        Ok(rx
+            .filter_map(move |payload| {
+                ready(
+                    decode(encoding, &payload)
+                        .map_err(|e| warn!("Unable to decode {}: {}", topic, e))
+                        .ok(),
+                )
+            })
+            .boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(yaml: &str) -> MqttPlugin {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn secured(security_type: &str) -> MqttPlugin {
+        plugin(&format!(
+            "{{enabled: true, host: broker, security: {{security-type: {}}}}}",
+            security_type
+        ))
+    }
+
+    #[test]
+    fn options() {
+        let json = plugin("{enabled: true, host: broker, encoding: json}");
+        assert_eq!(json.encoding, Encoding::Json);
+        assert_eq!(qos(&json).unwrap(), QoS::AtLeastOnce);
+        let options = mqtt_options(&json).unwrap();
+        assert_eq!(
+            options.broker_address(),
+            ("broker".to_string(), DEFAULT_PORT)
+        );
+        assert_eq!(options.client_id(), DEFAULT_CLIENT_ID);
+
+        assert!(qos(&plugin("{enabled: true, host: broker, qos: 3}")).is_err());
+        assert!(mqtt_options(&secured("none")).is_ok());
+        assert!(mqtt_options(&secured("user_password")).is_err());
+        assert!(mqtt_options(&secured("tls_client_auth")).is_err());
+        assert!(mqtt_options(&secured("jwt_creds")).is_err());
+    }
+
+    #[test]
+    fn topics() {
+        let mrid = Uuid::parse_str("5f7436ec-7bf2-4f23-a5c4-fbf25d909ba2").unwrap();
+        assert_eq!(
+            topic_name("SwitchDiscreteControlProfile", &mrid),
+            "openfmb/switchmodule/SwitchDiscreteControlProfile/5f7436ec-7bf2-4f23-a5c4-fbf25d909ba2"
+        );
+    }
+}
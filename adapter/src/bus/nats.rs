@@ -0,0 +1,201 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::*;
+use async_nats::{Client, ConnectOptions};
+use futures::future::ready;
+use log::warn;
+
+const DEFAULT_CONNECT_URL: &str = "nats://localhost:4222";
+
+/// NATS subject of a profile, as used by the other OpenFMB adapters
+pub fn subject(profile: &str, mrid: &Uuid) -> String {
+    format!("openfmb.switchmodule.{}.{}", profile, mrid)
+}
+
+fn required(value: &Option<String>, name: &str) -> Result<String, BusError> {
+    value
+        .clone()
+        .ok_or_else(|| BusError::Connect(format!("NATS security requires {}", name)))
+}
+
+/// Connect options for the `security` section of the `nats` plugin
+pub async fn connect_options(
+    security: &Option<SecuritySettings>,
+) -> Result<ConnectOptions, BusError> {
+    let options = ConnectOptions::new().name("udp-adapter");
+    let security = match security {
+        Some(security) => security,
+        None => return Ok(options),
+    };
+
+    let tls = |options: ConnectOptions| match &security.ca_trusted_cert_file {
+        Some(file) => options.require_tls(true).add_root_certificates(file.into()),
+        None => options.require_tls(true),
+    };
+
+    match security.security_type.as_str() {
+        "none" => Ok(options),
+        "tls_server_auth" => Ok(tls(options)),
+        "tls_client_auth" => {
+            let cert = required(&security.client_cert_chain_file, "client-cert-chain-file")?;
+            let key = required(&security.client_private_key_file, "client-private-key-file")?;
+            Ok(tls(options).add_client_certificate(cert.into(), key.into()))
+        }
+        "jwt_creds" => {
+            let creds = required(&security.jwt_creds_file, "jwt-creds-file")?;
+            let options = match &security.ca_trusted_cert_file {
+                Some(_) => tls(options),
+                None => options,
+            };
+            options
+                .credentials_file(&creds)
+                .await
+                .map_err(|e| BusError::Connect(format!("{}: {}", creds, e)))
+        }
+        "user_password" => {
+            let user = required(&security.user_name, "username")?;
+            let password = required(&security.password, "password")?;
+            let options = match &security.ca_trusted_cert_file {
+                Some(_) => tls(options),
+                None => options,
+            };
+            Ok(options.user_and_password(user, password))
+        }
+        other => Err(BusError::Connect(format!(
+            "Unknown NATS security type: {}",
+            other
+        ))),
+    }
+}
+
+/// NATS connection configured by the `nats` plugin section
+#[derive(Clone)]
+pub struct NatsMessageBus {
+    client: Client,
+    plugin: NatsPlugin,
+}
+
+impl NatsMessageBus {
+    pub async fn connect(plugin: NatsPlugin) -> Result<NatsMessageBus, BusError> {
+        let url = plugin
+            .connect_url
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONNECT_URL.to_string());
+        let client = connect_options(&plugin.security)
+            .await?
+            .connect(url.as_str())
+            .await
+            .map_err(|e| BusError::Connect(format!("{}: {}", url, e)))?;
+        Ok(NatsMessageBus { client, plugin })
+    }
+
+    /// Connect to the server if NATS is enabled
+    pub async fn from_config(
+        adapter_config: &AdapterConfig,
+    ) -> Result<Option<NatsMessageBus>, BusError> {
+        let plugin = match adapter_config
+            .plugins
+            .as_ref()
+            .and_then(|p| p.nats.as_ref())
+        {
+            Some(plugin) if plugin.enabled => plugin,
+            _ => return Ok(None),
+        };
+        NatsMessageBus::connect(plugin.clone()).await.map(Some)
+    }
+}
+
+#[async_trait]
+impl MessageBus for NatsMessageBus {
+    fn name(&self) -> &'static str {
+        "NATS"
+    }
+
+    fn is_publishing(&self, profile: &str, mrid: &str) -> bool {
+        is_topic_enabled(&self.plugin.publish, profile, mrid)
+    }
+
+    fn is_subscribing(&self, profile: &str, mrid: &str) -> bool {
+        is_topic_enabled(&self.plugin.subscribe, profile, mrid)
+    }
+
+    async fn publish(
+        &self,
+        profile: &str,
+        mrid: &Uuid,
+        message: ProfileMessage,
+    ) -> Result<(), BusError> {
+        self.client
+            .publish(
+                subject(profile, mrid),
+                message.encode(self.plugin.encoding)?.into(),
+            )
+            .await
+            .map_err(|e| BusError::Publish(e.to_string()))
+    }
+
+    async fn subscribe(&self, profile: &str, mrid: &Uuid) -> Result<ControlStream, BusError> {
+        let subscription = self
+            .client
+            .subscribe(subject(profile, mrid))
+            .await
+            .map_err(|e| BusError::Subscribe(e.to_string()))?;
+
+        let encoding = self.plugin.encoding;
+        Ok(subscription
+            .filter_map(move |msg| {
+                ready(
+                    decode(encoding, &msg.payload)
+                        .map_err(|e| warn!("Unable to decode {}: {}", msg.subject, e))
+                        .ok(),
+                )
+            })
+            .boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn security(security_type: &str) -> Option<SecuritySettings> {
+        Some(SecuritySettings {
+            security_type: security_type.to_string(),
+            ca_trusted_cert_file: None,
+            client_private_key_file: None,
+            client_cert_chain_file: None,
+            password: None,
+            user_name: None,
+            jwt_creds_file: None,
+        })
+    }
+
+    #[test]
+    fn subjects() {
+        let mrid = Uuid::parse_str("5f7436ec-7bf2-4f23-a5c4-fbf25d909ba2").unwrap();
+        assert_eq!(
+            subject("SwitchReadingProfile", &mrid),
+            "openfmb.switchmodule.SwitchReadingProfile.5f7436ec-7bf2-4f23-a5c4-fbf25d909ba2"
+        );
+    }
+
+    #[tokio::test]
+    async fn security_settings() {
+        assert!(connect_options(&None).await.is_ok());
+        assert!(connect_options(&security("none")).await.is_ok());
+        assert!(connect_options(&security("tls_server_auth")).await.is_ok());
+        assert!(connect_options(&security("tls_client_auth")).await.is_err());
+        assert!(connect_options(&security("jwt_creds")).await.is_err());
+        assert!(connect_options(&security("user_password")).await.is_err());
+        assert!(connect_options(&security("kerberos")).await.is_err());
+
+        let mut settings = security("user_password");
+        if let Some(s) = settings.as_mut() {
+            s.user_name = Some("adapter".to_string());
+            s.password = Some("secret".to_string());
+        }
+        assert!(connect_options(&settings).await.is_ok());
+    }
+}
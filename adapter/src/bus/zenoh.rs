@@ -0,0 +1,112 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::*;
+use futures::future::ready;
+use futures::Stream;
+use openfmb::bus::{Publisher, Subscriber, ZenohBus};
+use openfmb::encoding::{JsonEncoding, ProtobufEncoding};
+use std::fmt::Debug;
+
+/// Zenoh bus in the encoding of the `zenoh` plugin section
+#[derive(Clone)]
+enum Session {
+    Protobuf(ZenohBus<ProtobufEncoding>),
+    Json(ZenohBus<JsonEncoding>),
+}
+
+/// Zenoh session configured by the `zenoh` plugin section
+#[derive(Clone)]
+pub struct ZenohMessageBus {
+    session: Session,
+    plugin: ZenohPlugin,
+}
+
+impl ZenohMessageBus {
+    pub fn new(plugin: ZenohPlugin) -> ZenohMessageBus {
+        let session = match plugin.encoding {
+            Encoding::Protobuf => Session::Protobuf(ZenohBus::<ProtobufEncoding>::new()),
+            Encoding::Json => Session::Json(ZenohBus::<JsonEncoding>::new()),
+        };
+        ZenohMessageBus { session, plugin }
+    }
+
+    /// Open a session if Zenoh is enabled
+    pub fn from_config(adapter_config: &AdapterConfig) -> Option<ZenohMessageBus> {
+        let plugin = adapter_config.plugins.as_ref()?.zenoh.as_ref()?;
+        match plugin.enabled {
+            true => Some(ZenohMessageBus::new(plugin.clone())),
+            false => None,
+        }
+    }
+}
+
+/// Payload a Zenoh session publishes for `message` in `encoding`, to check it against `decode`
+#[cfg(test)]
+pub(crate) fn zenoh_payload(encoding: Encoding, message: &ProfileMessage) -> Vec<u8> {
+    use openfmb::encoding::Encoding as _;
+    match (encoding, message) {
+        (Encoding::Protobuf, ProfileMessage::Reading(m)) => ProtobufEncoding::encode(m).unwrap(),
+        (Encoding::Protobuf, ProfileMessage::Status(m)) => ProtobufEncoding::encode(m).unwrap(),
+        (Encoding::Json, ProfileMessage::Reading(m)) => JsonEncoding::encode(m).unwrap(),
+        (Encoding::Json, ProfileMessage::Status(m)) => JsonEncoding::encode(m).unwrap(),
+    }
+}
+
+/// Control profiles of a subscription, skipping those that fail to decode
+fn controls<S, E, F>(subscription: Result<S, E>) -> Result<ControlStream, BusError>
+where
+    S: Stream<Item = Result<SwitchDiscreteControlProfile, F>> + Send + 'static,
+    E: Debug,
+{
+    let subscription = subscription.map_err(|e| BusError::Subscribe(format!("{:?}", e)))?;
+    Ok(subscription.filter_map(|ctl| ready(ctl.ok())).boxed())
+}
+
+#[async_trait]
+impl MessageBus for ZenohMessageBus {
+    fn name(&self) -> &'static str {
+        "Zenoh"
+    }
+
+    fn is_publishing(&self, profile: &str, mrid: &str) -> bool {
+        is_topic_enabled(&self.plugin.publish, profile, mrid)
+    }
+
+    fn is_subscribing(&self, profile: &str, mrid: &str) -> bool {
+        is_topic_enabled(&self.plugin.subscribe, profile, mrid)
+    }
+
+    async fn publish(
+        &self,
+        profile: &str,
+        mrid: &Uuid,
+        message: ProfileMessage,
+    ) -> Result<(), BusError> {
+        let topic = topic(profile, mrid);
+        let result = match (self.session.clone(), message) {
+            (Session::Protobuf(mut bus), ProfileMessage::Reading(m)) => {
+                bus.publish(topic.iter(), m).await
+            }
+            (Session::Protobuf(mut bus), ProfileMessage::Status(m)) => {
+                bus.publish(topic.iter(), m).await
+            }
+            (Session::Json(mut bus), ProfileMessage::Reading(m)) => {
+                bus.publish(topic.iter(), m).await
+            }
+            (Session::Json(mut bus), ProfileMessage::Status(m)) => {
+                bus.publish(topic.iter(), m).await
+            }
+        };
+        result.map_err(|e| BusError::Publish(e.to_string()))
+    }
+
+    async fn subscribe(&self, profile: &str, mrid: &Uuid) -> Result<ControlStream, BusError> {
+        let topic = topic(profile, mrid);
+        match self.session.clone() {
+            Session::Protobuf(mut bus) => controls(bus.subscribe(topic.iter()).await),
+            Session::Json(mut bus) => controls(bus.subscribe(topic.iter()).await),
+        }
+    }
+}
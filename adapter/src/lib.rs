@@ -0,0 +1,50 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+pub mod bus;
+pub mod processors;
+
+use log::error;
+use oes::{Profile, ReportPolicy};
+
+/// Profiles in the `profiles` section of a stack configuration file, each with its mapping and
+/// optional report-by-exception settings
+pub fn read_profiles(contents: &str) -> Vec<Profile> {
+    let yaml = serde_yaml::from_str::<serde_yaml::Value>(contents).unwrap();
+
+    let profiles = match &yaml["profiles"] {
+        serde_yaml::Value::Sequence(profiles) => profiles,
+        _ => {
+            error!("Unable to parse profiles section in template file.");
+            return vec![];
+        }
+    };
+
+    profiles
+        .iter()
+        .map(|p| {
+            let profile_name = p
+                .get(&serde_yaml::Value::String("name".to_string()))
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string();
+
+            let profile_yaml_string = serde_yaml::to_string(&p).unwrap();
+
+            // Optional report-by-exception settings next to the mapping
+            let report = p.get("report").map(|r| {
+                serde_yaml::from_value::<ReportPolicy>(r.clone()).unwrap_or_else(|e| {
+                    panic!("Invalid report settings for {}: {}", profile_name, e)
+                })
+            });
+
+            Profile {
+                name: profile_name,
+                content: profile_yaml_string,
+                report,
+            }
+        })
+        .collect()
+}
@@ -2,13 +2,11 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-mod processors;
-
 use adapter_util::*;
 use log::{error, info};
-use oes::{Connector, Profile, StackConfiguration};
-use processors::*;
+use oes::{Connector, StackConfiguration};
 use std::{env, fs};
+use udp_adapter::{bus::Bus, processors::*, read_profiles};
 
 use ctrlc;
 use std::sync::mpsc::channel;
@@ -39,22 +37,19 @@ async fn main() {
 
     let adapter_config = serde_yaml::from_str::<AdapterConfig>(&contents).unwrap();
 
-    let zenoh = match zenoh_enabled(&adapter_config) {
-        Some(b) => {
-            if b {
-                Some(openfmb::bus::ZenohBus::<openfmb::encoding::ProtobufEncoding>::new())
-            } else {
-                None
-            }
-        }
-        None => None,
-    };
+    let bus = Bus::from_config(&adapter_config)
+        .await
+        .unwrap_or_else(|e| panic!("{}", e));
 
-    if zenoh.is_none() {
-        // zenoh is not enabled
-        panic!("Zenoh is not enabled.  Enable Zenoth in configuration file.");
+    if bus.is_empty() {
+        // no message bus is enabled
+        panic!("No message bus is enabled.  Enable one in configuration file.");
     }
 
+    // Indication processors save their state when told to shut down
+    let (shutdown, shutdown_rx) = tokio::sync::watch::channel(false);
+    let mut indications = vec![];
+
     match adapter_config.plugins.as_ref().unwrap().client.as_ref() {
         Some(plugin) => {
             if plugin.enabled {
@@ -66,62 +61,44 @@ async fn main() {
 
                     let mut stack_config =
                         serde_yaml::from_str::<StackConfiguration>(&contents).unwrap();
-
-                    let yaml = serde_yaml::from_str::<serde_yaml::Value>(&contents).unwrap();
+                    if let Err(e) = stack_config.validate() {
+                        panic!("ERROR:: Invalid configuration in {}: {}", path, e);
+                    }
 
                     // Create shared connector
                     let connector = Connector::connect(&stack_config).await.unwrap();
 
-                    match &yaml["profiles"] {
-                        serde_yaml::Value::Sequence(profiles) => {
-                            for p in profiles {
-                                //let stack_config = stack_config.clone();
-                                //let adapter_config = adapter_config.clone();
-
-                                let profile_name = p
-                                    .get(&serde_yaml::Value::String("name".to_string()))
-                                    .unwrap()
-                                    .as_str()
-                                    .unwrap()
-                                    .to_string();
-
-                                let profile_yaml_string = serde_yaml::to_string(&p).unwrap();
-
-                                if profile_name == "SwitchDiscreteControlProfile" {
-                                    let zenoh_bus = zenoh.clone();
-                                    let ctor = connector.clone();
-
-                                    let stack_config = stack_config.clone();
-                                    let adapter_config = adapter_config.clone();
-
-                                    // process control
-                                    let _t = std::thread::Builder::new()
-                                        .stack_size(STACK_SIZE)
-                                        .spawn(move || {
-                                            futures::executor::block_on(process_switch_control(
-                                                adapter_config,
-                                                stack_config,
-                                                Bus {
-                                                    zenoh_bus: zenoh_bus,
-                                                },
-                                                ctor,
-                                                Profile {
-                                                    name: profile_name,
-                                                    content: profile_yaml_string,
-                                                },
-                                            ));
-                                        });
-                                } else {
-                                    stack_config.profiles.push(Profile {
-                                        name: profile_name.clone(),
-                                        content: profile_yaml_string.clone(),
-                                    });
-                                }
-                            }
-                        }
-                        _ => {
-                            error!("Unable to parse profiles section in template file.");
-                        }
+                    let (controls, indications): (Vec<_>, Vec<_>) = read_profiles(&contents)
+                        .into_iter()
+                        .partition(|p| p.name == "SwitchDiscreteControlProfile");
+                    stack_config.profiles.extend(indications);
+
+                    // Control processors report failed commands to the indication processor,
+                    // which publishes the unknown status
+                    let (unknown, unknown_rx) = tokio::sync::mpsc::unbounded_channel();
+
+                    for profile in controls {
+                        let bus = bus.clone();
+                        let ctor = connector.clone();
+
+                        let stack_config = stack_config.clone();
+                        let adapter_config = adapter_config.clone();
+                        let unknown = unknown.clone();
+
+                        // process control
+                        let _t =
+                            std::thread::Builder::new()
+                                .stack_size(STACK_SIZE)
+                                .spawn(move || {
+                                    futures::executor::block_on(process_switch_control(
+                                        adapter_config,
+                                        stack_config,
+                                        bus,
+                                        ctor,
+                                        profile,
+                                        unknown,
+                                    ));
+                                });
                     }
 
                     // process
@@ -129,20 +106,24 @@ async fn main() {
                     let ctor = connector.clone();
                     let stack_config = stack_config.clone();
 
-                    let zenoh_bus = zenoh.clone();
+                    let bus = bus.clone();
+                    let shutdown = shutdown_rx.clone();
 
-                    let _t = std::thread::Builder::new()
+                    let t = std::thread::Builder::new()
                         .stack_size(STACK_SIZE)
                         .spawn(move || {
                             futures::executor::block_on(process_switch_indication(
                                 adapter_config,
                                 stack_config.clone(),
-                                Bus {
-                                    zenoh_bus: zenoh_bus,
-                                },
+                                bus,
                                 ctor,
+                                shutdown,
+                                unknown_rx,
                             ));
                         });
+                    if let Ok(t) = t {
+                        indications.push(t);
+                    }
                 }
             }
         }
@@ -156,4 +137,10 @@ async fn main() {
         .expect("Error setting Ctrl-C handler");
 
     rx.recv().expect("Could not receive from channel.");
+
+    info!("Shutting down...");
+    let _ = shutdown.send(true);
+    for t in indications {
+        let _ = t.join();
+    }
 }
@@ -0,0 +1,174 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::Received;
+use adapter_util::get_host_timestamp;
+use oes::{
+    PlugStatus, Reading, StackConfiguration, Tag, TagValue, OES_PLUG_CURRENT, OES_PLUG_POWER,
+    OES_PLUG_STATUS, OES_PLUG_VOLTAGE,
+};
+use openfmb::messages::commonmodule::{DetailQual, Quality};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often liveness is checked when no timeout is configured
+const IDLE_CHECK_PERIOD: Duration = Duration::from_secs(60);
+
+struct PlugLiveness {
+    last_seen: Instant,
+    received: Received,
+    stale: bool,
+}
+
+/// Tags of a plug that has not been heard from: unknown status and no measurements, all invalid
+fn never_seen_tags() -> Vec<Tag> {
+    vec![
+        Tag::new(
+            OES_PLUG_STATUS,
+            TagValue::Enum(PlugStatus::Unknown.position()),
+        ),
+        Tag::new(OES_PLUG_POWER, TagValue::F64(0.0)),
+        Tag::new(OES_PLUG_VOLTAGE, TagValue::F64(0.0)),
+        Tag::new(OES_PLUG_CURRENT, TagValue::F64(0.0)),
+    ]
+    .into_iter()
+    .map(Tag::with_invalid_quality)
+    .collect()
+}
+
+/// Tracks when each plug was last heard from
+pub struct Liveness {
+    timeout: Option<Duration>,
+    plugs: HashMap<String, PlugLiveness>,
+}
+
+impl Liveness {
+    /// Track the monitored plugs and outlets of `stack_config`.  Each starts out as never seen,
+    /// so one that never sends a heartbeat is published as stale once the timeout has passed.
+    pub fn new(stack_config: &StackConfiguration) -> Liveness {
+        let mut liveness = Liveness {
+            timeout: stack_config.liveness_timeout(),
+            plugs: HashMap::new(),
+        };
+        let timestamp = get_host_timestamp();
+        for plug in &stack_config.uncontrollable_plugs {
+            let outlets = plug.outlets.iter().map(|o| Some(o.index));
+            for outlet in std::iter::once(None).chain(outlets) {
+                liveness.heartbeat(&Received {
+                    reading: Reading {
+                        mac_address: plug.mac_address.clone(),
+                        outlet,
+                        tags: never_seen_tags(),
+                        ..Default::default()
+                    },
+                    at: Instant::now(),
+                    timestamp: timestamp.clone(),
+                });
+            }
+        }
+        liveness
+    }
+
+    /// How often `expire` should be called
+    pub fn check_period(&self) -> Duration {
+        match self.timeout {
+            Some(timeout) => (timeout / 4).max(Duration::from_millis(100)),
+            None => IDLE_CHECK_PERIOD,
+        }
+    }
+
+    /// Record a reading received from a plug
+    pub fn heartbeat(&mut self, received: &Received) {
+        self.plugs.insert(
+            received.reading.device_id(),
+            PlugLiveness {
+                last_seen: Instant::now(),
+                received: received.clone(),
+                stale: false,
+            },
+        );
+    }
+
+    /// Return the last reading of plugs that went silent since the previous call
+    pub fn expire(&mut self) -> Vec<Received> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return vec![],
+        };
+
+        let mut expired = vec![];
+        for plug in self.plugs.values_mut() {
+            if !plug.stale && plug.last_seen.elapsed() > timeout {
+                plug.stale = true;
+                expired.push(plug.received.clone());
+            }
+        }
+        expired
+    }
+}
+
+pub fn good_quality() -> Quality {
+    Quality {
+        validity: 1, // ValidityKind::ValidityKind_good
+        ..Default::default()
+    }
+}
+
+/// `quality` with its validity set to invalid
+pub fn invalid_quality(quality: &Quality) -> Quality {
+    Quality {
+        validity: 2, // ValidityKind::ValidityKind_invalid
+        ..quality.clone()
+    }
+}
+
+pub fn stale_quality() -> Quality {
+    Quality {
+        validity: 2, // ValidityKind::ValidityKind_invalid
+        detail_qual: Some(DetailQual {
+            old_data: true,
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oes::{OESOutlet, OESPlug};
+
+    #[test]
+    fn never_seen_plugs_expire() {
+        let config = StackConfiguration {
+            liveness_timeout_ms: Some(10),
+            uncontrollable_plugs: vec![OESPlug {
+                mac_address: "80c955640001".to_string(),
+                outlets: vec![OESOutlet {
+                    index: 1,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut liveness = Liveness::new(&config);
+        assert!(liveness.expire().is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        let mut expired = liveness
+            .expire()
+            .iter()
+            .map(|r| r.reading.device_id())
+            .collect::<Vec<_>>();
+        expired.sort();
+        assert_eq!(expired, vec!["80c955640001", "80c955640001/1"]);
+
+        // Never seen plugs carry their tags, so the stale quality is published with them
+        let received = &liveness.plugs["80c955640001"].received;
+        assert!(!received.reading.tags.is_empty());
+        assert!(received.reading.tags.iter().all(|t| t.invalid));
+        assert!(liveness.expire().is_empty());
+    }
+}
@@ -2,35 +2,101 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::bus::Bus;
 use adapter_util::*;
-use core::str::FromStr;
 use log::{debug, error};
 use oes::connector::Connector as OESConnector;
 use oes::{
-    messages::{parse_message, set_relay_message, OES_PLUG_COMMAND},
-    StackConfiguration,
+    messages::{Data, OpRequest, OpResponse, RelayReply, Request, OES_PLUG_COMMAND},
+    DatagramError, DatagramStats, OESPlug, Reading, ReplayGuard, ReportFilter, SourceValidation,
+    StackConfiguration, Tag, TagValue,
 };
+use openfmb::messages::commonmodule::{Quality, Timestamp};
 use openfmb::prelude::*;
-use std::net::SocketAddr;
-use std::time::Duration;
-use tokio::time::sleep;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{
+    mpsc::{Sender, UnboundedReceiver, UnboundedSender},
+    watch,
+};
+use tokio::time::{interval, sleep, timeout_at};
+
+pub mod liveness;
+pub use liveness::*;
 
 pub mod switch;
 pub use switch::*;
 
 #[derive(Clone, Debug)]
 pub struct Commands {
-    pub values: Vec<String>,
+    pub values: Vec<Request>,
     pub ts: Option<CommandTimestamp>,
     pub tolerance_ms: Option<u32>,
-    pub socket_address: SocketAddr,
+    pub plug: OESPlug,
+    /// Outlet addressed on a multi-outlet plug
+    pub outlet: Option<u32>,
+    /// Counters of replies to commands, separate from those of heartbeats
+    pub replay_guard: Arc<Mutex<ReplayGuard>>,
+    /// Allow sending to broadcast addresses
+    pub broadcast: bool,
+}
+
+/// A reading and when it was received
+#[derive(Clone, Debug)]
+pub struct Received {
+    pub reading: Reading,
+    pub at: Instant,
+    /// Receive time stamped with the quality of the host clock
+    pub timestamp: Timestamp,
+}
+
+impl Received {
+    /// Device-supplied timestamp, or the receive time if the device did not send a valid one
+    pub fn timestamp(&self) -> Timestamp {
+        self.reading
+            .timestamp
+            .as_deref()
+            .and_then(parse_utc_time)
+            .unwrap_or_else(|| self.timestamp.clone())
+    }
+
+    /// Device-supplied quality, or good quality if the device did not send one
+    pub fn quality(&self) -> Quality {
+        self.reading
+            .quality
+            .as_deref()
+            .map(BitString::to_quality)
+            .unwrap_or_else(good_quality)
+    }
+}
+
+/// Outcome of delivering commands to a plug
+#[derive(Clone, Debug)]
+pub enum CommandResult {
+    /// The plug acknowledged every command; holds the last reply
+    Success(Data),
+    /// The plug did not answer with the requested state within the retry policy
+    Timeout,
+    /// The plug refused the command with a NAK
+    Rejected(String),
+    /// The command could not be sent, e.g. the plug address did not resolve
+    Failed(String),
 }
 
+/// MAC address and outlet of a plug whose relay state is unknown after a failed command.  Control
+/// processors send these to the indication processor, which publishes the unknown status through
+/// its report filters.
+pub type UnknownStatus = (String, Option<u32>);
+
 pub async fn process_switch_indication(
     adapter_config: AdapterConfig,
     stack_config: StackConfiguration,
     bus: Bus,
     connector: OESConnector,
+    shutdown: watch::Receiver<bool>,
+    unknown: UnboundedReceiver<UnknownStatus>,
 ) {
     let mut processor = SwitchProcessor {
         bus: bus,
@@ -39,7 +105,7 @@ pub async fn process_switch_indication(
         stack_config: stack_config.clone(),
     };
 
-    processor.process_indication().await;
+    processor.process_indication(shutdown, unknown).await;
 }
 
 pub async fn process_switch_control(
@@ -48,6 +114,7 @@ pub async fn process_switch_control(
     bus: Bus,
     connector: OESConnector,
     profile: oes::Profile,
+    unknown: UnboundedSender<UnknownStatus>,
 ) {
     let mut processor = SwitchProcessor {
         bus: bus,
@@ -56,60 +123,224 @@ pub async fn process_switch_control(
         stack_config: stack_config.clone(),
     };
 
-    processor.process_control(profile).await;
+    processor.process_control(profile, unknown).await;
 }
 
-#[derive(Debug, Clone)]
-pub struct Bus {
-    pub zenoh_bus: Option<openfmb::bus::ZenohBus<openfmb::encoding::ProtobufEncoding>>,
+async fn do_send_commands(commands: Commands) -> CommandResult {
+    // Resolve on every command so plugs configured by hostname can move
+    let address = match commands.plug.resolve().await {
+        Ok(address) => address,
+        Err(e) => {
+            return CommandResult::Failed(format!(
+                "unable to resolve {}: {}",
+                commands.plug.ip_address, e
+            ))
+        }
+    };
+
+    let sock = match OESConnector::bind_any_for(&address).await {
+        Some(sock) => sock,
+        None => return CommandResult::Failed("unable to bind to local socket".to_string()),
+    };
+
+    // Group commands are answered from the member's own address, so only unicast sockets can be
+    // connected
+    let group = commands.plug.group_address || is_group_address(&address.ip());
+    if commands.broadcast {
+        if let Err(e) = sock.set_broadcast(true) {
+            return CommandResult::Failed(format!("unable to enable broadcast: {}", e));
+        }
+    }
+    if !group {
+        if let Err(e) = sock.connect(address).await {
+            return CommandResult::Failed(format!("unable to connect to {}: {}", address, e));
+        }
+    }
+
+    let mut result = CommandResult::Timeout;
+    for request in &commands.values {
+        result = send_command(
+            &sock,
+            request,
+            &commands.plug,
+            &commands.replay_guard,
+            &address,
+            group,
+        )
+        .await;
+
+        // Do not send the rest of the batch once the plug failed to follow a command
+        if !matches!(result, CommandResult::Success(_)) {
+            break;
+        }
+    }
+    result
 }
 
-async fn do_send_commands(commands: Commands) {
-    match OESConnector::bind_any().await {
-        Some(sock) => {
-            match sock.connect(commands.socket_address).await {
-                Ok(_) => {
-                    for c in commands.values {
-                        for _n in 1..3 {
-                            // for retry
-                            match sock.send(c.as_bytes()).await {
-                                Ok(_) => {
-                                    debug!("Sent {} to {}", &c, &commands.socket_address);
-                                    break;
-                                }
-                                Err(e) => error!(
-                                    "Failed to send {} to {} with error: {}",
-                                    &c, &commands.socket_address, e
-                                ),
-                            }
-                        }
-                    }
-                }
-                Err(e) => error!("Unable to connecto to {}: {}", commands.socket_address, e),
+/// Whether `ip` is always a group address: a multicast address or the IPv4 limited broadcast
+/// address.  Subnet broadcast addresses depend on the netmask and are marked on the plug instead.
+fn is_group_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_multicast() || ip.is_broadcast(),
+        IpAddr::V6(ip) => ip.is_multicast(),
+    }
+}
+
+/// Wait up to `wait` for a reply from the plug at `address`.  Replies from anyone else are
+/// dropped: a unicast reply must come from `address`, and a reply to a group address must carry
+/// the plug's MAC address, so a NAK to a group address is dropped as well.
+async fn receive_reply(
+    sock: &UdpSocket,
+    plug: &OESPlug,
+    replay_guard: &Mutex<ReplayGuard>,
+    address: &SocketAddr,
+    group: bool,
+    wait: Duration,
+) -> Option<RelayReply> {
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut buf = [0u8; 2048];
+
+    loop {
+        let (len, source) = match timeout_at(deadline, sock.recv_from(&mut buf)).await {
+            Ok(Ok(received)) => received,
+            Ok(Err(e)) => {
+                error!("Failed to receive reply from {}: {}", address, e);
+                return None;
             }
+            Err(_) => return None,
+        };
+        if !group && source.ip() != address.ip() {
+            log::warn!("Dropped reply for {} from {}", address, source);
+            continue;
         }
-        None => {
-            error!("Unable to bind to local socket");
+        match plug.decode_datagram::<RelayReply>(&buf[0..len], replay_guard) {
+            Ok(RelayReply::State(reply))
+                if group
+                    && !plug.mac_address.is_empty()
+                    && !reply.mac_address.eq_ignore_ascii_case(&plug.mac_address) =>
+            {
+                log::warn!(
+                    "Dropped reply for {} from {} ({})",
+                    plug.mac_address,
+                    reply.mac_address,
+                    source
+                );
+            }
+            Ok(RelayReply::Op(reply)) if group => {
+                log::warn!(
+                    "Dropped {:?} for {} from {}",
+                    reply,
+                    plug.mac_address,
+                    source
+                );
+            }
+            Ok(reply) => return Some(reply),
+            Err(e) => log::warn!("Invalid reply from {}: {}", source, e),
         }
     }
 }
 
-pub async fn execute_commands(commands: Commands) {
+/// Send a single request and wait for the plug to acknowledge it.  Unanswered requests and
+/// replies with another state are retried per the policy; a NAK rejects the request at once.
+async fn send_command(
+    sock: &UdpSocket,
+    request: &Request,
+    plug: &OESPlug,
+    replay_guard: &Mutex<ReplayGuard>,
+    address: &SocketAddr,
+    group: bool,
+) -> CommandResult {
+    let policy = plug.retry_policy();
+    let mut backoff = policy.backoff;
+
+    for attempt in 0..=policy.retries {
+        if attempt > 0 {
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        // Encode on every attempt so that authenticated retries carry a fresh counter
+        let msg = match plug.encode_datagram(request) {
+            Ok(msg) => msg,
+            Err(e) => return CommandResult::Failed(e.to_string()),
+        };
+
+        match sock.send_to(&msg, address).await {
+            Ok(_) => debug!(
+                "Sent {:?} to {} (attempt {})",
+                request,
+                address,
+                attempt + 1
+            ),
+            Err(e) => {
+                error!(
+                    "Failed to send {:?} to {} with error: {}",
+                    request, address, e
+                );
+                continue;
+            }
+        }
+
+        match receive_reply(sock, plug, replay_guard, address, group, policy.timeout).await {
+            Some(RelayReply::State(reply)) if request.is_acknowledged_by(&reply) => {
+                return CommandResult::Success(reply)
+            }
+            Some(RelayReply::State(reply)) => log::warn!(
+                "Requested {:?} from {} but plug reported {} (attempt {})",
+                request.fc,
+                address,
+                request.reported_status(&reply),
+                attempt + 1
+            ),
+            Some(RelayReply::Op(OpResponse::NAK { reason, .. })) => {
+                return CommandResult::Rejected(reason)
+            }
+            Some(RelayReply::Op(reply)) => log::warn!(
+                "Unexpected reply {:?} from {} (attempt {})",
+                reply,
+                address,
+                attempt + 1
+            ),
+            None => log::warn!(
+                "No reply from {} within {:?} (attempt {})",
+                address,
+                policy.timeout,
+                attempt + 1
+            ),
+        }
+    }
+
+    CommandResult::Timeout
+}
+
+pub async fn execute_commands(commands: Commands) -> CommandResult {
     debug!("Command count: {}", commands.values.len());
-    match commands.ts {
+    let address = commands.plug.ip_address.clone();
+    let result = match commands.ts {
         Some(_time) => {
             // handle schedule
-            schedule(commands).await;
+            schedule(commands).await
+        }
+        _ => do_send_commands(commands).await,
+    };
+
+    match &result {
+        CommandResult::Success(reply) => {
+            debug!("Plug {} acknowledged commands: {}", address, reply)
+        }
+        CommandResult::Timeout => error!("Plug {} did not acknowledge commands", address),
+        CommandResult::Rejected(reason) => {
+            error!("Plug {} rejected commands: {}", address, reason)
         }
-        _ => {
-            do_send_commands(commands).await;
+        CommandResult::Failed(reason) => {
+            error!("Unable to send commands to plug {}: {}", address, reason)
         }
     }
+    result
 }
 
-async fn schedule(commands: Commands) {
+async fn schedule(commands: Commands) -> CommandResult {
     let now = get_current_timestamp().seconds;
-    let mut ok = true;
     let seconds = commands.ts.unwrap().seconds;
     if seconds > now {
         sleep(Duration::from_secs(seconds - now)).await;
@@ -119,113 +350,203 @@ async fn schedule(commands: Commands) {
             _ => 0,
         };
         if (now - seconds) * 1000 < tolerance {
-            ok = false;
             error!(
                 "The schedule time is not within the allowed tolerance: {} (tolerance: {})",
                 seconds, tolerance
             );
+            return CommandResult::Rejected("schedule time is out of tolerance".to_string());
         }
     }
 
-    if ok {
-        do_send_commands(commands).await;
+    do_send_commands(commands).await
+}
+
+/// Hand tags with their quality and timestamp to a profile visitor.  Tags without a mapping are
+/// ignored.
+pub fn apply_tags<T: prost::Message, V: ConfigReadVisitor<T>>(
+    visitor: &mut V,
+    p: &mut T,
+    tags: &[Tag],
+    quality: &Quality,
+    timestamp: &Timestamp,
+) {
+    for tag in tags {
+        match &tag.value {
+            TagValue::F64(v) => visitor.update_f64(&tag.name, p, *v),
+            TagValue::Bool(v) => visitor.update_boolean(&tag.name, p, *v),
+            TagValue::String(v) => visitor.update_string(&tag.name, p, v.clone()),
+            // Enum setters take the mapped value as a bit string
+            TagValue::Enum(v) => visitor.update_string(&tag.name, p, to_bit_string(*v)),
+        }
+        let quality = match tag.invalid {
+            true => invalid_quality(quality),
+            false => quality.clone(),
+        };
+        visitor.update_quality(&tag.quality_name(), p, quality);
+        visitor.update_timestamp(&tag.timestamp_name(), p, timestamp.clone());
     }
 }
 
-pub fn zenoh_enabled(adapter_config: &AdapterConfig) -> Option<bool> {
-    Some(adapter_config.plugins.as_ref()?.zenoh.as_ref()?.enabled)
+/// Profiles an update is published to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PublishTo {
+    All,
+    Reading,
+    Status,
 }
 
-pub fn zenoh_publishing_topics_enabled(
-    adapter_config: &AdapterConfig,
-    profile: &str,
-    mrid: &str,
-) -> Option<bool> {
-    match zenoh_enabled(adapter_config) {
-        Some(b) => {
-            if !b {
-                return Some(false);
-            }
-        }
-        None => {
-            return None;
-        }
+/// Check a profile's report policy; profiles without one publish every update
+pub fn should_report(
+    filter: &mut Option<ReportFilter<Quality>>,
+    device_id: &str,
+    tags: &[Tag],
+    quality: &Quality,
+) -> bool {
+    match filter {
+        Some(filter) => filter.should_publish(device_id, tags, quality, Instant::now()),
+        None => true,
     }
+}
 
-    let list: Vec<ZenohTopic> = match adapter_config
-        .plugins
-        .as_ref()?
-        .zenoh
-        .as_ref()?
-        .publish
-        .as_ref()
-    {
-        Some(v) => v.to_vec(),
-        _ => {
-            vec![]
-        }
-    };
+/// Status tags, i.e. everything but measurements
+pub fn discrete_tags(tags: &[Tag]) -> Vec<Tag> {
+    tags.iter()
+        .filter(|t| !matches!(t.value, TagValue::F64(_)))
+        .cloned()
+        .collect()
+}
 
-    for v in list {
-        if v.profile == profile {
-            if &v.subject == "*" || &v.subject == mrid {
-                debug!("Zenoh publication to {}.{} is enabled.", profile, mrid);
-                return Some(true);
+/// Receive heartbeats and status reports on the adapter socket and forward them via channel
+/// together with the time they were received
+pub async fn receive_datagrams(
+    sock: Arc<UdpSocket>,
+    config: StackConfiguration,
+    stats: Arc<DatagramStats>,
+    replay_guard: Arc<Mutex<ReplayGuard>>,
+    tx: Sender<Received>,
+) {
+    // Subscribe to UDP messages and publish OpenFMB messages
+    let mut buf = [0u8; 2048];
+    while let Ok((len, addr)) = sock.recv_from(&mut buf).await {
+        let at = Instant::now();
+        let timestamp = get_host_timestamp();
+        log::debug!("RECEIVED: {} bytes from {}", len, addr);
+        DatagramStats::increment(&stats.received);
+
+        // Decode with the settings of the plug at the source address
+        let sender = config.lookup_plug_for_address(&addr);
+        let readings = match config.decode_readings(&buf[0..len], &addr, &replay_guard) {
+            Ok(readings) => readings,
+            Err(DatagramError::Auth(e)) => {
+                let count = DatagramStats::increment(&stats.auth_failures);
+                log::warn!(
+                    "Rejected unauthenticated datagram from {} ({} so far): {}",
+                    addr,
+                    count,
+                    e
+                );
+                continue;
             }
-        }
-    }
-    error!("Zenoh publication to {}.{} is disabled.", profile, mrid);
-    None
-}
+            Err(DatagramError::Decode(e)) => {
+                let count = DatagramStats::increment(&stats.decode_errors);
+                log::warn!("Invalid datagram from {} ({} so far): {}", addr, count, e);
+                continue;
+            }
+        };
+
+        // Outlet readings come from the same device as the first one
+        let reading = &readings[0];
 
-pub fn zenoh_subscribing_topics_enabled(
-    adapter_config: &AdapterConfig,
-    profile: &str,
-    mrid: &str,
-) -> Option<bool> {
-    match zenoh_enabled(adapter_config) {
-        Some(b) => {
-            if !b {
-                return Some(false);
+        // A plug with a key only accepts data sealed with its own key
+        if let Some(plug) = config.lookup_uncontrollable(&reading.mac_address) {
+            let sealed_by_plug = sender
+                .map(|s| s.is_authenticated() && s.mac_address == plug.mac_address)
+                .unwrap_or(false);
+            if plug.is_authenticated() && !sealed_by_plug {
+                let count = DatagramStats::increment(&stats.auth_failures);
+                log::warn!(
+                    "Rejected datagram for {} not authenticated by its key, from {} ({} so far)",
+                    reading.mac_address,
+                    addr,
+                    count
+                );
+                continue;
             }
         }
-        None => {
-            return None;
+
+        if config.source_validation != SourceValidation::Disabled
+            && !config.is_expected_source(&reading.mac_address, &addr)
+        {
+            let count = DatagramStats::increment(&stats.source_mismatches);
+            match config.source_validation {
+                SourceValidation::Drop => {
+                    log::warn!(
+                        "Dropped datagram for {} from unexpected source {} ({} so far)",
+                        reading.mac_address,
+                        addr,
+                        count
+                    );
+                    continue;
+                }
+                _ => log::warn!(
+                    "Datagram for {} came from unexpected source {} ({} so far)",
+                    reading.mac_address,
+                    addr,
+                    count
+                ),
+            }
         }
-    }
 
-    let list: Vec<ZenohTopic> = match adapter_config
-        .plugins
-        .as_ref()?
-        .zenoh
-        .as_ref()?
-        .subscribe
-        .as_ref()
-    {
-        Some(v) => v.to_vec(),
-        _ => {
-            vec![]
+        // Look up mrid from MAC address
+        for reading in readings {
+            let received = Received {
+                reading,
+                at,
+                timestamp: timestamp.clone(),
+            };
+            if let Err(e) = tx.send(received).await {
+                log::error!("{}", e);
+            }
         }
-    };
+    }
+}
 
-    for v in list {
-        if v.profile == profile {
-            if &v.subject == "*" || &v.subject == mrid {
-                debug!("Zenoh subscription to {}.{} is enabled.", profile, mrid);
-                return Some(true);
+/// Periodically send a status request to a plug.  The request goes out on the adapter socket so the
+/// plug's status report is received and published the same way as a heartbeat.
+pub async fn poll_status(sock: Arc<UdpSocket>, plug: OESPlug, period: Duration) {
+    let mut ticker = interval(period);
+    loop {
+        ticker.tick().await;
+        let address = match plug.resolve().await {
+            Ok(address) => address,
+            Err(e) => {
+                error!("Unable to resolve {}: {}", plug.ip_address, e);
+                continue;
             }
+        };
+        let msg = match plug.encode_datagram(&OpRequest::SR) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Unable to encode status request for {}: {}", address, e);
+                return;
+            }
+        };
+        match sock.send_to(&msg, address).await {
+            Ok(_) => debug!("Sent status request to {}", address),
+            Err(e) => error!("Failed to send status request to {}: {}", address, e),
         }
     }
-    error!("Zenoh subscription to {}.{} is disabled.", profile, mrid);
-    None
 }
 
 fn to_commands(
     commands: Vec<Command>,
     tolerance_ms: Option<u32>,
-    socket_address: SocketAddr,
+    plug: OESPlug,
+    outlet: Option<u32>,
+    replay_guard: Arc<Mutex<ReplayGuard>>,
+    broadcast: bool,
 ) -> Option<Commands> {
-    let mut list: Vec<String> = Vec::new();
+    let mut list: Vec<Request> = Vec::new();
 
     let mut ts: Option<CommandTimestamp> = None;
     for r in commands {
@@ -239,11 +560,7 @@ fn to_commands(
                     match c.name.as_str() {
                         OES_PLUG_COMMAND => {
                             if let Some(val) = c.real_value {
-                                if val > 0.0 {
-                                    list.push(set_relay_message(true));
-                                } else {
-                                    list.push(set_relay_message(false));
-                                }
+                                list.push(Request::relay(val > 0.0).with_outlet(outlet));
                             }
                         }
                         _ => {
@@ -261,11 +578,7 @@ fn to_commands(
                     match c.name.as_str() {
                         OES_PLUG_COMMAND => {
                             if let Some(val) = c.bool_value {
-                                if val {
-                                    list.push(set_relay_message(true));
-                                } else {
-                                    list.push(set_relay_message(false));
-                                }
+                                list.push(Request::relay(val).with_outlet(outlet));
                             }
                         }
                         _ => {
@@ -286,44 +599,28 @@ fn to_commands(
             values: list,
             ts,
             tolerance_ms,
-            socket_address,
+            plug,
+            outlet,
+            replay_guard,
+            broadcast,
         }),
     }
 }
 
-#[macro_export]
-macro_rules! publish_profile {
-    ($processor:expr, $profile_name:expr, $msg:expr) => {
-        match $processor
-            .bus
-            .zenoh_bus
-            .clone()
-            .unwrap()
-            .publish(
-                topic($profile_name, &$msg.device_mrid().unwrap()).iter(),
-                $msg,
-            )
-            .await
-        {
-            Ok(_) => {
-                debug!("{} published!", $profile_name);
-            }
-            Err(e) => {
-                error!("Zenoh::Failed to publish message: {}", e)
-            }
-        }
-    };
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_addresses() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(is_group_address(&ip("239.255.85.56")));
+        assert!(is_group_address(&ip("ff02::1")));
+        assert!(is_group_address(&ip("255.255.255.255")));
 
-#[macro_export]
-macro_rules! subscribe_profile {
-    ($processor:expr, $profile_name:expr, $mrid:expr) => {{
-        $processor
-            .bus
-            .zenoh_bus
-            .clone()
-            .unwrap()
-            .subscribe(topic($profile_name, $mrid).iter())
-            .await
-    }};
+        // A host address ending in .255 is unicast on subnets of /23 and larger
+        assert!(!is_group_address(&ip("192.168.1.255")));
+        assert!(!is_group_address(&ip("192.168.1.20")));
+        assert!(!is_group_address(&ip("fd00::30")));
+    }
 }
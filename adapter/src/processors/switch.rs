@@ -3,20 +3,21 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::*;
-use crate::{publish_profile, subscribe_profile};
 use futures::stream::StreamExt;
 use oes::connector::Connector as OESConnector;
 use oes::{
-    Profile, StackConfiguration, OES_PLUG_CURRENT, OES_PLUG_POWER, OES_PLUG_STATUS,
-    OES_PLUG_VOLTAGE,
+    Aggregator, EnergyMeter, PlugStatus, Profile, ReportFilter, StackConfiguration, Tag,
+    HOST_REFRESH_PERIOD, OES_PLUG_POWER, OES_PLUG_STATUS,
 };
 
 use log::error;
-use openfmb::bus::{Publisher, Subscriber};
+use openfmb::messages::commonmodule::Quality;
 
-use openfmb_messages_ext::OpenFMBExt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::interval;
 use uuid::Uuid;
 
 #[derive(Clone)]
@@ -29,149 +30,297 @@ pub struct SwitchProcessor {
 
 impl SwitchProcessor {
     /// Process indication (reading/status).  We have to process all profiles here because UDP package is broadcasted to a specific port
-    pub async fn process_indication(&mut self) {
+    pub async fn process_indication(
+        &mut self,
+        mut shutdown: watch::Receiver<bool>,
+        mut unknown: UnboundedReceiver<UnknownStatus>,
+    ) {
         // Handle reading and status messages
         let (tx, mut rx) = mpsc::channel(100);
 
         let runtime = Runtime::new().unwrap();
-
-        let sock = self.connector.sock.clone();
+        // Timers below are driven by this runtime
+        let _guard = runtime.enter();
 
         // Spawn a thread to listen to the socket and send data via channel
-        runtime.spawn(async move {
-            // Subscribe to UDP messages and publish OpenFMB messages
-            let mut buf = [0u8; 1024];
-            while let Ok((len, _addr)) = sock.recv_from(&mut buf).await {
-                let msg = std::str::from_utf8(&buf).unwrap();
-
-                log::debug!("RECEIVED: {} ({})", msg, len);
-
-                match parse_message(&buf, len) {
-                    Ok(data) => {
-                        // Look up mrid from MAC address
-                        match tx.send(data).await {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("{}", e);
+        runtime.spawn(receive_datagrams(
+            self.connector.sock.clone(),
+            self.stack_config.clone(),
+            self.connector.stats.clone(),
+            self.connector.heartbeat_guard.clone(),
+            tx,
+        ));
+
+        // Poll plugs that are configured for active status requests
+        for plug in &self.stack_config.uncontrollable_plugs {
+            if let Some(period) = self.stack_config.poll_interval(plug) {
+                log::info!("Polling {} every {:?}", plug.ip_address, period);
+                runtime.spawn(poll_status(
+                    self.connector.sock.clone(),
+                    plug.clone(),
+                    period,
+                ));
+            }
+        }
+
+        // Keep the addresses of plug hostnames current for matching datagram sources
+        let hostnames = self.stack_config.hostnames();
+        if !hostnames.is_empty() {
+            runtime.spawn(
+                self.stack_config
+                    .hosts
+                    .clone()
+                    .refresh(hostnames, HOST_REFRESH_PERIOD),
+            );
+        }
+
+        let stack_config = self.stack_config.clone();
+        let mut liveness = Liveness::new(&stack_config);
+        let mut liveness_check = interval(liveness.check_period());
+        let energy_file = stack_config.energy_file.as_ref().map(PathBuf::from);
+        let mut energy = EnergyMeter::open(energy_file, stack_config.liveness_timeout())
+            .unwrap_or_else(|e| {
+                log::error!(
+                    "Unable to load energy totals from {:?}, totals will not be saved: {}",
+                    stack_config.energy_file,
+                    e
+                );
+                EnergyMeter::open(None, stack_config.liveness_timeout()).unwrap()
+            });
+        let mut reports = stack_config
+            .profiles
+            .iter()
+            .map(|p| p.report.clone().map(ReportFilter::new))
+            .collect::<Vec<_>>();
+
+        // Reading profiles are published at a fixed rate when aggregating
+        let mut aggregator = stack_config.aggregation.clone().map(Aggregator::new);
+        let mut aggregation_tick = interval(
+            stack_config
+                .aggregation
+                .as_ref()
+                .map(|a| a.window())
+                .unwrap_or(Duration::from_secs(60)),
+        );
+        loop {
+            tokio::select! {
+                // Upon receiving UDP message, publish OpenFMB messages
+                Some(received) = rx.recv() => {
+                    let reading = &received.reading;
+                    if stack_config.lookup_mrid(&reading.mac_address, reading.outlet).is_some() {
+                        liveness.heartbeat(&received);
+                        let mut tags = reading.tags.clone();
+                        if let Some(power) = reading.f64(OES_PLUG_POWER) {
+                            let totals = energy.update(&reading.device_id(), power, received.at);
+                            energy.save_if_due();
+                            tags.extend(totals.tags());
+                        }
+                        self.save_replay_counters(false);
+                        let target = match &mut aggregator {
+                            Some(aggregator) => {
+                                aggregator.add(reading);
+                                PublishTo::Status
                             }
+                            None => PublishTo::All,
+                        };
+                        self.publish_data(&received, &tags, &received.quality(), &mut reports, target).await;
+                    }
+                }
+                // Publish the statistics of the window that just ended
+                _ = aggregation_tick.tick(), if aggregator.is_some() => {
+                    let timestamp = get_host_timestamp();
+                    for reading in aggregator.as_mut().map(|a| a.flush()).unwrap_or_default() {
+                        let mut tags = reading.tags.clone();
+                        if let Some(totals) = energy.totals(&reading.device_id()) {
+                            tags.extend(totals.tags());
                         }
+                        let received = Received {
+                            reading,
+                            at: Instant::now(),
+                            timestamp: timestamp.clone(),
+                        };
+                        self.publish_data(&received, &tags, &received.quality(), &mut reports, PublishTo::Reading).await;
                     }
-                    Err(e) => print!("{}", e),
+                }
+                // Republish plugs that stopped sending heartbeats with stale quality
+                _ = liveness_check.tick() => {
+                    for received in liveness.expire() {
+                        let reading = &received.reading;
+                        log::warn!("No heartbeat from {} within timeout, marking data as old", reading.device_id());
+                        let mut tags = reading.tags.clone();
+                        if let Some(totals) = energy.totals(&reading.device_id()) {
+                            tags.extend(totals.tags());
+                        }
+                        // Old data keeps the time it was measured at
+                        self.publish_data(&received, &tags, &stale_quality(), &mut reports, PublishTo::All).await;
+                    }
+                    energy.save_if_due();
+                    self.save_replay_counters(false);
+                }
+                // The relay state is unknown after a failed command until the plug reports again
+                Some((mac_address, outlet)) = unknown.recv() => {
+                    self.publish_unknown_status(mac_address, outlet, &mut reports).await;
+                }
+                // Keep the energy accumulated since the last save
+                _ = shutdown.changed() => {
+                    if let Err(e) = energy.save() {
+                        log::error!("Unable to save energy totals: {}", e);
+                    }
+                    self.save_replay_counters(true);
+                    return;
                 }
             }
-        });
+        }
+    }
+
+    /// Publish reading and status profiles configured for the plug or outlet of `received` with
+    /// `tags`.  Profiles with a report policy in `reports` are only published on significant
+    /// change, status profiles only when the status changes.
+    async fn publish_data(
+        &mut self,
+        received: &Received,
+        tags: &[Tag],
+        quality: &Quality,
+        reports: &mut [Option<ReportFilter<Quality>>],
+        target: PublishTo,
+    ) {
+        let reading = &received.reading;
+        let timestamp = received.timestamp();
+        let id = match self
+            .stack_config
+            .lookup_mrid(&reading.mac_address, reading.outlet)
+        {
+            Some(id) => id,
+            None => return,
+        };
 
-        let stack_config = self.stack_config.clone();
-        let adapter_config = self.adapter_config.clone();
         let builder = Builder {};
-        loop {
-            // Upon receiving UDP message, publish OpenFMB messages
-            if let Some(data) = rx.recv().await {
-                if let Some(id) = stack_config.lookup_mrid_for_uncontrollable(&data.mac_address) {
-                    for profile in &stack_config.profiles {
-                        match builder.build(&profile.name, &profile.content) {
-                            VisitorType::SwitchReading(_s, mut p, mut visitor) => {
-                                match visitor.device_mrid() {
-                                    Some(device_mrid) => {
-                                        // Make sure the publish topic is configured
-                                        if zenoh_publishing_topics_enabled(
-                                            &adapter_config,
-                                            &profile.name,
-                                            &device_mrid,
-                                        )
-                                        .is_some()
-                                        {
-                                            if device_mrid == id {
-                                                // found and matched mRID
-                                                log::debug!(
-                                                    "Found mRID {} from MAC {}",
-                                                    id,
-                                                    &data.mac_address
-                                                );
-                                                visitor.visit(&mut p);
-
-                                                visitor.update_f64(
-                                                    OES_PLUG_POWER,
-                                                    &mut p,
-                                                    data.power,
-                                                );
-                                                visitor.update_f64(
-                                                    OES_PLUG_VOLTAGE,
-                                                    &mut p,
-                                                    data.voltage,
-                                                );
-                                                visitor.update_f64(
-                                                    OES_PLUG_CURRENT,
-                                                    &mut p,
-                                                    data.current,
-                                                );
-
-                                                publish_profile!(self, &profile.name, p);
-                                            }
-                                        } else {
-                                            log::info!("Publish topic {}.{} is not configured.  Check main adapter configuration file.", profile.name, device_mrid);
-                                        }
-                                    }
-                                    None => {
-                                        log::error!(
-                                            "Missing device MRID in config file for {}",
-                                            &profile.name
-                                        )
+        let profiles = self.stack_config.profiles.clone();
+        for (index, profile) in profiles.iter().enumerate() {
+            match builder.build(&profile.name, &profile.content) {
+                VisitorType::SwitchReading(_s, mut p, mut visitor)
+                    if target != PublishTo::Status =>
+                {
+                    match visitor.device_mrid() {
+                        Some(device_mrid) => {
+                            // Make sure the publish topic is configured
+                            if self.bus.is_publishing(&profile.name, &device_mrid) {
+                                if device_mrid == id {
+                                    // found and matched mRID
+                                    log::debug!("Found mRID {} from {}", id, reading.device_id());
+                                    if !should_report(
+                                        &mut reports[index],
+                                        &reading.device_id(),
+                                        tags,
+                                        quality,
+                                    ) {
+                                        continue;
                                     }
+                                    visitor.visit(&mut p);
+
+                                    apply_tags(&mut visitor, &mut p, tags, quality, &timestamp);
+
+                                    self.bus.publish(&profile.name, p).await;
                                 }
+                            } else {
+                                log::info!("Publish topic {}.{} is not configured.  Check main adapter configuration file.", profile.name, device_mrid);
                             }
-                            VisitorType::SwitchStatus(_s, mut p, mut visitor) => {
-                                match visitor.device_mrid() {
-                                    Some(device_mrid) => {
-                                        // Make sure the publish topic is configured
-                                        if zenoh_publishing_topics_enabled(
-                                            &adapter_config,
-                                            &profile.name,
-                                            &device_mrid,
-                                        )
-                                        .is_some()
-                                        {
-                                            if device_mrid == id {
-                                                // found and matched mRID
-                                                log::debug!(
-                                                    "Found mRID {} from MAC {}",
-                                                    id,
-                                                    &data.mac_address
-                                                );
-                                                visitor.visit(&mut p);
-                                                let status = data.status.clone();
-                                                let status = (status as usize).to_string();
-
-                                                visitor.update_string(
-                                                    OES_PLUG_STATUS,
-                                                    &mut p,
-                                                    status,
-                                                );
-
-                                                publish_profile!(self, &profile.name, p);
-                                            }
-                                        } else {
-                                            log::info!("Publish topic {}.{} is not configured.  Check main adapter configuration file.", profile.name, device_mrid);
-                                        }
-                                    }
-                                    None => {
-                                        log::error!(
-                                            "Missing device MRID in config file for {}",
-                                            &profile.name
-                                        )
+                        }
+                        None => {
+                            log::error!("Missing device MRID in config file for {}", &profile.name)
+                        }
+                    }
+                }
+                VisitorType::SwitchStatus(_s, mut p, mut visitor)
+                    if target != PublishTo::Reading =>
+                {
+                    match visitor.device_mrid() {
+                        Some(device_mrid) => {
+                            // Make sure the publish topic is configured
+                            if self.bus.is_publishing(&profile.name, &device_mrid) {
+                                if device_mrid == id {
+                                    // found and matched mRID
+                                    log::debug!("Found mRID {} from {}", id, reading.device_id());
+                                    if !should_report(
+                                        &mut reports[index],
+                                        &reading.device_id(),
+                                        &discrete_tags(tags),
+                                        quality,
+                                    ) {
+                                        continue;
                                     }
+                                    visitor.visit(&mut p);
+                                    apply_tags(&mut visitor, &mut p, tags, quality, &timestamp);
+
+                                    self.bus.publish(&profile.name, p).await;
                                 }
+                            } else {
+                                log::info!("Publish topic {}.{} is not configured.  Check main adapter configuration file.", profile.name, device_mrid);
                             }
-                            _ => {}
+                        }
+                        None => {
+                            log::error!("Missing device MRID in config file for {}", &profile.name)
                         }
                     }
                 }
+                _ => {}
+            }
+        }
+    }
+
+    /// Write the counters of authenticated plugs to disk, when due or `now`
+    fn save_replay_counters(&self, now: bool) {
+        for guard in [&self.connector.heartbeat_guard, &self.connector.reply_guard] {
+            let mut guard = guard.lock().unwrap();
+            if !now {
+                guard.save_if_due();
+            } else if let Err(e) = guard.save() {
+                log::error!("Unable to save replay counters: {}", e);
             }
         }
     }
 
+    /// Publish the status of a plug or outlet as unknown, with invalid quality, after a command
+    /// to it failed.  The report filters in `reports` see the unknown status, so the next status
+    /// report of the plug is published even when its position did not change.
+    async fn publish_unknown_status(
+        &mut self,
+        mac_address: String,
+        outlet: Option<u32>,
+        reports: &mut [Option<ReportFilter<Quality>>],
+    ) {
+        let status = Tag::new(
+            OES_PLUG_STATUS,
+            TagValue::Enum(PlugStatus::Unknown.position()),
+        )
+        .with_invalid_quality();
+        let received = Received {
+            reading: Reading {
+                mac_address,
+                outlet,
+                tags: vec![status],
+                ..Default::default()
+            },
+            at: Instant::now(),
+            timestamp: get_host_timestamp(),
+        };
+        let tags = received.reading.tags.clone();
+        self.publish_data(
+            &received,
+            &tags,
+            &received.quality(),
+            reports,
+            PublishTo::Status,
+        )
+        .await;
+    }
+
     /// Process control
-    pub async fn process_control(&mut self, profile: Profile) {
+    pub async fn process_control(
+        &mut self,
+        profile: Profile,
+        unknown: UnboundedSender<UnknownStatus>,
+    ) {
         let builder = Builder {};
         let runtime = Runtime::new().unwrap();
         let myself = self.clone();
@@ -182,38 +331,42 @@ impl SwitchProcessor {
                     match visitor.device_mrid() {
                         Some(device_mrid) => {
                             // Make sure the subscription topic is configured
-                            if zenoh_subscribing_topics_enabled(
-                                    &myself.adapter_config,
-                                    &profile.name,
-                                    &device_mrid,
-                                )
-                                .is_some()
-                            {
+                            if myself.bus.is_subscribing(&profile.name, &device_mrid) {
                                 // Check against list of "controllable plugs" see if we can control with the mRID
-                                match myself.stack_config.lookup_socker_address_for_controllable(&device_mrid){
-                                    Some(addr) => {
+                                match myself.stack_config.lookup_controllable_target(&device_mrid).map(|(plug, outlet)| (plug.clone(), outlet)) {
+                                    Some((plug, outlet)) => {
                                         // Spawn a thread to subscribe to NATS/Zenoh for OpenFMB control messages
                                         let device_mrid = Uuid::parse_str(&device_mrid).unwrap();
-                                        let mut subscription = subscribe_profile!(myself, &profile.name, &device_mrid).unwrap();
+                                        let mut subscription = myself.bus.subscribe(&profile.name, &device_mrid).await;
                                         debug!("Subscribe to {}.{}...", &profile.name, &device_mrid);
-                                        while let Some(ctl) = subscription.next().await {
-                                            if let Ok(mut ctl) = ctl {
-                                                log::debug!("Got SwitchDiscreteControlProfile message: {:?}", ctl);
-                                                visitor.visit(&mut ctl);
-                                                let results = visitor.execute_commands(&mut ctl);
-                                                log::debug!("Commands: {:?}", results);
-                                                let tolerance_ms = visitor.get_tolerance_ms();
-
-                                                match to_commands(results, tolerance_ms, addr.clone()) {
-                                                    Some(commands) => {
-                                                        // Send command to the plug
-                                                        log::debug!("Sending {:?}", commands);
-                                                        tokio::spawn(async move {
-                                                            execute_commands(commands).await;
-                                                        });
-                                                    }
-                                                    None => {}
+                                        while let Some(mut ctl) = subscription.next().await {
+                                            log::debug!("Got SwitchDiscreteControlProfile message: {:?}", ctl);
+                                            visitor.visit(&mut ctl);
+                                            let results = visitor.execute_commands(&mut ctl);
+                                            log::debug!("Commands: {:?}", results);
+                                            let tolerance_ms = visitor.get_tolerance_ms();
+
+                                            match to_commands(results, tolerance_ms, plug.clone(), outlet, myself.connector.reply_guard.clone(), myself.stack_config.broadcast) {
+                                                Some(commands) => {
+                                                    // Send command to the plug
+                                                    log::debug!("Sending {:?}", commands);
+                                                    let unknown = unknown.clone();
+                                                    let mac_address = plug.mac_address.clone();
+                                                    tokio::spawn(async move {
+                                                        match execute_commands(commands).await {
+                                                            CommandResult::Success(reply) => {
+                                                                log::info!("Plug {} is now {}", reply.mac_address, reply.relay_status(outlet));
+                                                            }
+                                                            // The relay state is unknown until the plug reports again
+                                                            _ => {
+                                                                if let Err(e) = unknown.send((mac_address, outlet)) {
+                                                                    log::error!("{}", e);
+                                                                }
+                                                            }
+                                                        }
+                                                    });
                                                 }
+                                                None => {}
                                             }
                                         }
                                     }
@@ -241,12 +394,3 @@ impl SwitchProcessor {
         });
     }
 }
-
-/// Create profile topic
-fn topic(typ: &str, mrid: &Uuid) -> ProfileTopic {
-    ProfileTopic::new(
-        Module::SwitchModule,
-        openfmb::topic::Profile::from_str(typ).unwrap(),
-        mrid.clone(),
-    )
-}
@@ -0,0 +1,353 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Runs the processors against plugs on loopback UDP sockets, with an in-memory bus
+
+use adapter_util::AdapterConfig;
+use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use oes::{
+    Connector, Data, Faults, PlugSimulator, PlugStatus, Profile, Request, SimulatedPlug,
+    StackConfiguration, FC,
+};
+use openfmb::messages::commonmodule::{
+    ConductingEquipment, ControlDpc, DbPosKind, PhaseDpc, Quality,
+};
+use openfmb::messages::switchmodule::{
+    ProtectedSwitch, SwitchDiscreteControl, SwitchDiscreteControlProfile, SwitchDiscreteControlXswi,
+};
+use openfmb_messages_ext::OpenFMBExt;
+use rand::{rngs::StdRng, SeedableRng};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc::unbounded_channel, watch},
+    time::timeout,
+};
+use udp_adapter::{bus::*, processors::*, read_profiles};
+use uuid::Uuid;
+
+const TEMPLATE: &str = include_str!("../../template.yaml");
+const MAC_ADDRESS: &str = "80c955645cd4";
+const MRID: &str = "5f7436ec-7bf2-4f23-a5c4-fbf25d909ba2";
+const CONTROL_PROFILE: &str = "SwitchDiscreteControlProfile";
+const TIMEOUT: Duration = Duration::from_secs(10);
+const STACK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Bus that hands published profiles to the test and delivers the control profiles it sends
+#[derive(Clone)]
+struct MemoryBus {
+    published: UnboundedSender<ProfileMessage>,
+    subscribers: Arc<Mutex<Vec<UnboundedSender<SwitchDiscreteControlProfile>>>>,
+}
+
+impl MemoryBus {
+    fn new() -> (MemoryBus, UnboundedReceiver<ProfileMessage>) {
+        let (published, rx) = unbounded();
+        let bus = MemoryBus {
+            published,
+            subscribers: Arc::new(Mutex::new(vec![])),
+        };
+        (bus, rx)
+    }
+
+    /// Deliver a control profile to every subscription
+    fn send_control(&self, message: SwitchDiscreteControlProfile) {
+        for subscriber in self.subscribers.lock().unwrap().iter() {
+            let _ = subscriber.unbounded_send(message.clone());
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBus for MemoryBus {
+    fn name(&self) -> &'static str {
+        "Memory"
+    }
+
+    fn is_publishing(&self, _profile: &str, _mrid: &str) -> bool {
+        true
+    }
+
+    fn is_subscribing(&self, _profile: &str, _mrid: &str) -> bool {
+        true
+    }
+
+    async fn publish(
+        &self,
+        _profile: &str,
+        _mrid: &Uuid,
+        message: ProfileMessage,
+    ) -> Result<(), BusError> {
+        self.published
+            .unbounded_send(message)
+            .map_err(|e| BusError::Publish(e.to_string()))
+    }
+
+    async fn subscribe(&self, _profile: &str, _mrid: &Uuid) -> Result<ControlStream, BusError> {
+        let (tx, rx) = unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        Ok(rx.boxed())
+    }
+}
+
+/// The template stack with the adapter on an ephemeral loopback port
+async fn connect() -> (StackConfiguration, Connector) {
+    let mut config: StackConfiguration = serde_yaml::from_str(TEMPLATE).unwrap();
+    config.ip_address = "127.0.0.1".to_string();
+    config.port = 0;
+    config.energy_file = None;
+    let connector = Connector::connect(&config).await.unwrap();
+    (config, connector)
+}
+
+/// Point the plugs of the template at `address`
+fn use_plug_address(config: &mut StackConfiguration, address: SocketAddr) {
+    for plug in config
+        .uncontrollable_plugs
+        .iter_mut()
+        .chain(config.controllable_plugs.iter_mut())
+    {
+        plug.ip_address = address.ip().to_string();
+        plug.port = address.port() as u32;
+    }
+}
+
+fn profiles() -> Vec<Profile> {
+    read_profiles(TEMPLATE)
+        .into_iter()
+        .filter(|p| p.name != CONTROL_PROFILE)
+        .collect()
+}
+
+/// Run a processor on its own thread, the way `main` does
+fn spawn<F: Future<Output = ()> + Send + 'static>(processor: F) {
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || futures::executor::block_on(processor))
+        .unwrap();
+}
+
+/// Next published profile, failing the test when none arrives in time
+async fn next(published: &mut UnboundedReceiver<ProfileMessage>) -> ProfileMessage {
+    timeout(TIMEOUT, published.next())
+        .await
+        .expect("no profile published in time")
+        .expect("bus closed")
+}
+
+fn control_message(mrid: &Uuid, close: bool) -> SwitchDiscreteControlProfile {
+    SwitchDiscreteControlProfile {
+        protected_switch: Some(ProtectedSwitch {
+            conducting_equipment: Some(ConductingEquipment {
+                m_rid: mrid.to_string(),
+                ..Default::default()
+            }),
+        }),
+        switch_discrete_control: Some(SwitchDiscreteControl {
+            switch_discrete_control_xswi: Some(SwitchDiscreteControlXswi {
+                pos: Some(PhaseDpc {
+                    phs3: Some(ControlDpc { ctl_val: close }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn heartbeats_publish_reading_and_status_profiles() {
+    let (mut config, connector) = connect().await;
+    let plug = PlugSimulator::bind(
+        serde_yaml::from_str::<SimulatedPlug>(&format!(
+            "{{mac-address: {}, listen: 127.0.0.1:0, heartbeat-ms: 200, load-w: 60, voltage: 120}}",
+            MAC_ADDRESS
+        ))
+        .unwrap(),
+        connector.sock.local_addr().unwrap(),
+        &Faults::default(),
+        StdRng::seed_from_u64(1),
+    )
+    .await
+    .unwrap();
+    use_plug_address(&mut config, plug.local_addr().unwrap());
+    config.profiles = profiles();
+
+    let (bus, mut published) = MemoryBus::new();
+    let mrid = Uuid::parse_str(MRID).unwrap();
+
+    // The processor runs until the test ends and drops the sender
+    let (_shutdown, shutdown_rx) = watch::channel(false);
+    tokio::spawn(plug.run());
+    spawn(process_switch_indication(
+        AdapterConfig { plugins: None },
+        config,
+        Bus::new(vec![Arc::new(bus)]),
+        connector,
+        shutdown_rx,
+        unbounded_channel().1,
+    ));
+
+    // Without aggregation, each heartbeat publishes the reading and then the status profile
+    let reading = match next(&mut published).await {
+        ProfileMessage::Reading(reading) => reading,
+        other => panic!("expected SwitchReadingProfile, got {:?}", other),
+    };
+    assert_eq!(reading.device_mrid().unwrap(), mrid);
+    let power = reading.switch_reading[0]
+        .reading_mmxu
+        .as_ref()
+        .and_then(|mmxu| mmxu.w.as_ref())
+        .and_then(|w| w.net.as_ref())
+        .and_then(|net| net.c_val.as_ref())
+        .map(|c_val| c_val.mag)
+        .unwrap();
+    assert!((40.0..80.0).contains(&power), "power {}", power);
+
+    let status = match next(&mut published).await {
+        ProfileMessage::Status(status) => status,
+        other => panic!("expected SwitchStatusProfile, got {:?}", other),
+    };
+    assert_eq!(status.device_mrid().unwrap(), mrid);
+    let position = status
+        .switch_status
+        .as_ref()
+        .and_then(|s| s.switch_status_xswi.as_ref())
+        .and_then(|xswi| xswi.pos.as_ref())
+        .and_then(|pos| pos.phs3.as_ref())
+        .map(|phs3| phs3.st_val)
+        .unwrap();
+    assert_eq!(position, DbPosKind::Closed as i32);
+}
+
+/// Whether a published quality marks the value as invalid old data
+fn is_stale(quality: Option<&Quality>) -> bool {
+    quality
+        .map(|q| q.validity == 2 && q.detail_qual.as_ref().map(|d| d.old_data) == Some(true))
+        .unwrap_or(false)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn silent_plugs_are_published_as_stale() {
+    let (mut config, connector) = connect().await;
+    config.liveness_timeout_ms = Some(200);
+    config.profiles = profiles();
+
+    let (bus, mut published) = MemoryBus::new();
+    let mrid = Uuid::parse_str(MRID).unwrap();
+
+    // The plug never sends a heartbeat
+    let (_shutdown, shutdown_rx) = watch::channel(false);
+    spawn(process_switch_indication(
+        AdapterConfig { plugins: None },
+        config,
+        Bus::new(vec![Arc::new(bus)]),
+        connector,
+        shutdown_rx,
+        unbounded_channel().1,
+    ));
+
+    let reading = match next(&mut published).await {
+        ProfileMessage::Reading(reading) => reading,
+        other => panic!("expected SwitchReadingProfile, got {:?}", other),
+    };
+    assert_eq!(reading.device_mrid().unwrap(), mrid);
+    let power = reading.switch_reading[0]
+        .reading_mmxu
+        .as_ref()
+        .and_then(|mmxu| mmxu.w.as_ref())
+        .and_then(|w| w.net.as_ref());
+    assert!(is_stale(power.and_then(|net| net.q.as_ref())));
+
+    let status = match next(&mut published).await {
+        ProfileMessage::Status(status) => status,
+        other => panic!("expected SwitchStatusProfile, got {:?}", other),
+    };
+    assert_eq!(status.device_mrid().unwrap(), mrid);
+    let position = status
+        .switch_status
+        .as_ref()
+        .and_then(|s| s.switch_status_xswi.as_ref())
+        .and_then(|xswi| xswi.pos.as_ref())
+        .and_then(|pos| pos.phs3.as_ref());
+    assert!(is_stale(position.and_then(|phs3| phs3.q.as_ref())));
+}
+
+/// Send a control profile until the relay request it causes reaches the plug, then acknowledge
+/// the request like the plug firmware
+async fn switch(bus: &MemoryBus, plug: &UdpSocket, mrid: &Uuid, close: bool) -> Request {
+    let expected = match close {
+        true => FC::On,
+        false => FC::Off,
+    };
+    let mut buf = [0u8; 2048];
+    let (request, adapter) = timeout(TIMEOUT, async {
+        loop {
+            bus.send_control(control_message(mrid, close));
+            // Requests caused by earlier messages are skipped
+            while let Ok(received) =
+                timeout(Duration::from_millis(500), plug.recv_from(&mut buf)).await
+            {
+                let (len, adapter) = received.unwrap();
+                let request: Request = serde_json::from_slice(&buf[0..len]).unwrap();
+                if request.fc == expected {
+                    return (request, adapter);
+                }
+            }
+        }
+    })
+    .await
+    .expect("no relay datagram sent in time");
+
+    let reply = Data {
+        mac_address: MAC_ADDRESS.to_string(),
+        status: match close {
+            true => PlugStatus::On,
+            false => PlugStatus::Off,
+        },
+        ..Default::default()
+    };
+    plug.send_to(&serde_json::to_vec(&reply).unwrap(), adapter)
+        .await
+        .unwrap();
+    request
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn control_profiles_send_relay_datagrams() {
+    let (mut config, connector) = connect().await;
+    let plug = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    use_plug_address(&mut config, plug.local_addr().unwrap());
+    let control = read_profiles(TEMPLATE)
+        .into_iter()
+        .find(|p| p.name == CONTROL_PROFILE)
+        .unwrap();
+
+    let (bus, _published) = MemoryBus::new();
+    let mrid = Uuid::parse_str(MRID).unwrap();
+    spawn(process_switch_control(
+        AdapterConfig { plugins: None },
+        config,
+        Bus::new(vec![Arc::new(bus.clone())]),
+        connector,
+        control,
+        unbounded_channel().0,
+    ));
+
+    // Opening the switch turns the relay off, closing it turns the relay back on
+    let request = switch(&bus, &plug, &mrid, false).await;
+    assert_eq!(request.fc, FC::Off);
+    assert_eq!(request.outlet, None);
+    let request = switch(&bus, &plug, &mrid, true).await;
+    assert_eq!(request.fc, FC::On);
+}
@@ -0,0 +1,222 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::time::interval;
+
+/// How often hostnames resolved for source matching are looked up again
+pub const HOST_REFRESH_PERIOD: Duration = Duration::from_secs(60);
+
+/// Strip the brackets from an IPv6 literal such as `[::1]`
+pub fn normalize_host(host: &str) -> &str {
+    let host = host.trim();
+    host.strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+fn to_port(port: u32) -> io::Result<u16> {
+    u16::try_from(port).map_err(|_e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid port: {}", port),
+        )
+    })
+}
+
+/// Parse an IPv4 or IPv6 (bracketed or not) literal and port without name resolution
+pub fn parse_socket_address(host: &str, port: u32) -> io::Result<SocketAddr> {
+    let ip = normalize_host(host)
+        .parse::<IpAddr>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", host, e)))?;
+    Ok(SocketAddr::new(ip, to_port(port)?))
+}
+
+/// Resolve an IP literal or hostname and port to a socket address
+pub async fn resolve(host: &str, port: u32) -> io::Result<SocketAddr> {
+    let host = normalize_host(host);
+    tokio::net::lookup_host((host, to_port(port)?))
+        .await?
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No address found for {}", host),
+            )
+        })
+}
+
+/// Addresses of plug hostnames, read when matching datagram sources.  Clones share the same
+/// addresses, so the cache of a configuration is kept current for all of its copies.
+#[derive(Clone, Debug, Default)]
+pub struct HostCache(Arc<RwLock<HashMap<String, Vec<IpAddr>>>>);
+
+/// Resolved addresses are not part of the configuration
+impl PartialEq for HostCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl HostCache {
+    /// IP addresses of a host for matching datagram sources.  Literals are parsed directly;
+    /// hostnames are looked up in the addresses filled by `resolve`, so this never blocks on DNS.
+    pub fn ips(&self, host: &str) -> Vec<IpAddr> {
+        let host = normalize_host(host);
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return vec![ip.to_canonical()];
+        }
+        self.0
+            .read()
+            .unwrap()
+            .get(host)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn insert(&self, host: &str, ips: Vec<IpAddr>) {
+        self.0.write().unwrap().insert(host.to_string(), ips);
+    }
+
+    /// Resolve hostnames for `ips`.  A host that fails to resolve keeps its previous addresses.
+    pub async fn resolve(&self, hosts: &[String]) {
+        for host in hosts {
+            let host = normalize_host(host);
+            if host.parse::<IpAddr>().is_ok() {
+                continue;
+            }
+            match tokio::net::lookup_host((host, 0)).await {
+                Ok(addrs) => self.insert(host, addrs.map(|a| a.ip().to_canonical()).collect()),
+                Err(e) => log::error!("Failed to resolve {}: {}", host, e),
+            }
+        }
+    }
+
+    /// Resolve hostnames for `ips` again every `period`, so address changes are picked up
+    pub async fn refresh(self, hosts: Vec<String>, period: Duration) {
+        let mut ticker = interval(period);
+        // The first tick completes immediately, and the hosts were resolved at startup
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.resolve(&hosts).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connector, OESPlug, StackConfiguration};
+
+    fn config(ip_address: &str) -> StackConfiguration {
+        StackConfiguration {
+            ip_address: ip_address.to_string(),
+            port: 0,
+            controllable_plugs: vec![
+                OESPlug {
+                    ip_address: "[fd00::30]".to_string(),
+                    port: 8556,
+                    mrid: "v6".to_string(),
+                    ..Default::default()
+                },
+                OESPlug {
+                    ip_address: "plug.example".to_string(),
+                    port: 8556,
+                    mrid: "host".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_ipv4_and_ipv6_literals() {
+        assert_eq!(
+            parse_socket_address("192.168.86.30", 8556).unwrap(),
+            "192.168.86.30:8556".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_socket_address("fd00::30", 8556).unwrap(),
+            "[fd00::30]:8556".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_socket_address("[fd00::30]", 8556).unwrap(),
+            "[fd00::30]:8556".parse::<SocketAddr>().unwrap()
+        );
+        assert!(parse_socket_address("plug.local", 8556).is_err());
+        assert!(parse_socket_address("fd00::30", 70000).is_err());
+    }
+
+    #[tokio::test]
+    async fn resolves_literals() {
+        assert_eq!(
+            resolve("[fd00::30]", 8556).await.unwrap(),
+            "[fd00::30]:8556".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            resolve("192.168.86.30", 8556).await.unwrap(),
+            "192.168.86.30:8556".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            HostCache::default().ips("[::ffff:192.168.86.30]"),
+            vec![IpAddr::from([192, 168, 86, 30])]
+        );
+    }
+
+    #[test]
+    fn hostnames_are_matched_from_the_cache() {
+        let mut shared = config("127.0.0.1");
+        shared.uncontrollable_plugs = shared.controllable_plugs.clone();
+        let source = |ip: [u8; 4]| SocketAddr::from((ip, 8556));
+        assert!(shared
+            .lookup_plug_for_address(&source([192, 168, 86, 31]))
+            .is_none());
+
+        // Clones of the configuration share the resolved addresses
+        let copy = shared.clone();
+        shared
+            .hosts
+            .insert("plug.example", vec![IpAddr::from([192, 168, 86, 31])]);
+        let plug = copy.lookup_plug_for_address(&source([192, 168, 86, 31]));
+        assert_eq!(plug.map(|p| p.mrid.as_str()), Some("host"));
+        assert!(copy
+            .lookup_plug_for_address(&source([192, 168, 86, 30]))
+            .is_none());
+
+        // Other configurations keep their own addresses
+        assert!(config("127.0.0.1").hosts.ips("plug.example").is_empty());
+    }
+
+    #[tokio::test]
+    async fn plug_addresses_resolve_at_command_time() {
+        let config = config("127.0.0.1");
+        let plug = config.lookup_controllable("v6").unwrap();
+        assert_eq!(
+            plug.resolve().await.unwrap(),
+            "[fd00::30]:8556".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn binds_for_ipv4_and_ipv6() {
+        let config = StackConfiguration {
+            ip_address: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let connector = Connector::connect(&config).await.unwrap();
+        assert!(connector.sock.local_addr().unwrap().ip().is_loopback());
+
+        let remote = "[fd00::30]:8556".parse::<SocketAddr>().unwrap();
+        let sock = Connector::bind_any_for(&remote).await.unwrap();
+        assert!(sock.local_addr().unwrap().is_ipv6());
+    }
+}
@@ -0,0 +1,289 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use crate::{Reading, Tag, TagValue};
+
+/// Statistic of a numeric tag over an aggregation window
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum Statistic {
+    #[serde(rename = "min")]
+    Min,
+    #[serde(rename = "max")]
+    Max,
+    #[default]
+    #[serde(rename = "mean")]
+    Mean,
+    #[serde(rename = "last")]
+    Last,
+}
+
+/// Settings for publishing readings at a fixed rate, aggregated over a window
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AggregationConfiguration {
+    /// Length of the window, which is also the publishing period
+    #[serde(rename = "window-ms")]
+    pub window_ms: u64,
+
+    /// Statistic published for numeric tags
+    #[serde(rename = "statistic", default)]
+    pub statistic: Statistic,
+
+    /// Statistic for individual tags, overriding `statistic`
+    #[serde(rename = "tag-statistics", default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tag_statistics: BTreeMap<String, Statistic>,
+}
+
+impl AggregationConfiguration {
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms.max(1))
+    }
+
+    fn statistic(&self, tag: &str) -> Statistic {
+        self.tag_statistics
+            .get(tag)
+            .copied()
+            .unwrap_or(self.statistic)
+    }
+}
+
+struct Summary {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32,
+    last: f64,
+}
+
+impl Summary {
+    fn new(value: f64) -> Summary {
+        Summary {
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+            last: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+        self.last = value;
+    }
+
+    fn value(&self, statistic: Statistic) -> f64 {
+        match statistic {
+            Statistic::Min => self.min,
+            Statistic::Max => self.max,
+            Statistic::Mean => self.sum / self.count as f64,
+            Statistic::Last => self.last,
+        }
+    }
+}
+
+/// Tags of one device within the current window, in the order they were first seen
+struct Window {
+    last: Reading,
+    summaries: Vec<(Tag, Option<Summary>)>,
+}
+
+/// Collects readings and turns each window into one reading per device
+pub struct Aggregator {
+    config: AggregationConfiguration,
+    windows: HashMap<String, Window>,
+}
+
+impl Aggregator {
+    pub fn new(config: AggregationConfiguration) -> Aggregator {
+        Aggregator {
+            config,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Add a reading to the current window of its device
+    pub fn add(&mut self, reading: &Reading) {
+        let window = self
+            .windows
+            .entry(reading.device_id())
+            .or_insert_with(|| Window {
+                last: reading.clone(),
+                summaries: vec![],
+            });
+        window.last = reading.clone();
+
+        for tag in &reading.tags {
+            let entry = window
+                .summaries
+                .iter_mut()
+                .find(|(t, _)| t.name == tag.name);
+            match (entry, &tag.value) {
+                (Some((_, Some(summary))), TagValue::F64(v)) if !tag.invalid => summary.add(*v),
+                // Invalid samples are left out once the window has valid ones
+                (Some((_, Some(_))), TagValue::F64(_)) => {}
+                (Some(entry), _) => *entry = Self::summarize(tag),
+                (None, _) => window.summaries.push(Self::summarize(tag)),
+            }
+        }
+    }
+
+    /// Numeric tags with valid values are summarized, others keep their last value.  A numeric tag
+    /// is only published as invalid when none of its values in the window were valid.
+    fn summarize(tag: &Tag) -> (Tag, Option<Summary>) {
+        match tag.value {
+            TagValue::F64(v) if !tag.invalid => (tag.clone(), Some(Summary::new(v))),
+            _ => (tag.clone(), None),
+        }
+    }
+
+    /// End the current window: one reading per device that reported in it, with the configured
+    /// statistic of each numeric tag.  The readings carry no device timestamp; they are stamped
+    /// with the time of the window.
+    pub fn flush(&mut self) -> Vec<Reading> {
+        let mut readings: Vec<Reading> = self
+            .windows
+            .drain()
+            .map(|(_, window)| {
+                let tags = window
+                    .summaries
+                    .into_iter()
+                    .map(|(tag, summary)| match summary {
+                        Some(summary) => Tag {
+                            value: TagValue::F64(summary.value(self.config.statistic(&tag.name))),
+                            ..tag
+                        },
+                        None => tag,
+                    })
+                    .collect();
+                Reading {
+                    tags,
+                    timestamp: None,
+                    ..window.last
+                }
+            })
+            .collect();
+        readings.sort_by_key(|r| r.device_id());
+        readings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OES_PLUG_POWER, OES_PLUG_STATUS, OES_PLUG_VOLTAGE};
+
+    fn reading(mac_address: &str, status: i32, power: f64, voltage: f64) -> Reading {
+        Reading {
+            mac_address: mac_address.to_string(),
+            tags: vec![
+                Tag::new(OES_PLUG_STATUS, TagValue::Enum(status)),
+                Tag::new(OES_PLUG_POWER, TagValue::F64(power)),
+                Tag::new(OES_PLUG_VOLTAGE, TagValue::F64(voltage)),
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn config(statistic: Statistic) -> AggregationConfiguration {
+        AggregationConfiguration {
+            window_ms: 1000,
+            statistic,
+            tag_statistics: BTreeMap::new(),
+        }
+    }
+
+    fn aggregate(config: AggregationConfiguration) -> Vec<Reading> {
+        let mut aggregator = Aggregator::new(config);
+        aggregator.add(&reading("b", 0, 5.0, 119.0));
+        aggregator.add(&reading("a", 0, 10.0, 120.0));
+        aggregator.add(&reading("a", 1, 30.0, 122.0));
+        aggregator.add(&reading("a", 1, 20.0, 121.0));
+        aggregator.flush()
+    }
+
+    #[test]
+    fn statistics() {
+        let expected = [
+            (Statistic::Min, 10.0, 120.0),
+            (Statistic::Max, 30.0, 122.0),
+            (Statistic::Mean, 20.0, 121.0),
+            (Statistic::Last, 20.0, 121.0),
+        ];
+        for (statistic, power, voltage) in expected {
+            let readings = aggregate(config(statistic));
+            assert_eq!(readings.len(), 2);
+            assert_eq!(readings[0].mac_address, "a");
+            assert_eq!(readings[0].f64(OES_PLUG_POWER), Some(power));
+            assert_eq!(readings[0].f64(OES_PLUG_VOLTAGE), Some(voltage));
+            // Discrete tags keep their last value
+            assert_eq!(
+                readings[0].tags[0],
+                Tag::new(OES_PLUG_STATUS, TagValue::Enum(1))
+            );
+            assert_eq!(readings[1].f64(OES_PLUG_POWER), Some(5.0));
+        }
+    }
+
+    #[test]
+    fn per_tag_statistics_and_windows() {
+        let config: AggregationConfiguration = serde_json::from_str(
+            r#"{"window-ms": 1000, "tag-statistics": {"OES.Plug.Power": "max"}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.statistic, Statistic::Mean);
+
+        let mut aggregator = Aggregator::new(config);
+        aggregator.add(&reading("a", 0, 10.0, 120.0));
+        let mut stamped = reading("a", 0, 30.0, 122.0);
+        stamped.timestamp = Some("04/10/2021_19:34:06.582,[00000000]".to_string());
+        aggregator.add(&stamped);
+        let readings = aggregator.flush();
+        assert_eq!(readings[0].timestamp, None);
+        assert_eq!(readings[0].f64(OES_PLUG_POWER), Some(30.0));
+        assert_eq!(readings[0].f64(OES_PLUG_VOLTAGE), Some(121.0));
+
+        // Each window starts empty
+        assert!(aggregator.flush().is_empty());
+        aggregator.add(&reading("a", 0, 1.0, 118.0));
+        assert_eq!(aggregator.flush()[0].f64(OES_PLUG_POWER), Some(1.0));
+    }
+
+    fn invalid_power(mac_address: &str, power: f64) -> Reading {
+        let mut reading = reading(mac_address, 0, power, 121.0);
+        reading.tags[1] = reading.tags[1].clone().with_invalid_quality();
+        reading
+    }
+
+    #[test]
+    fn invalid_values_are_skipped() {
+        let mut aggregator = Aggregator::new(config(Statistic::Mean));
+        aggregator.add(&reading("a", 0, 10.0, 120.0));
+        aggregator.add(&invalid_power("a", 0.0));
+        aggregator.add(&reading("a", 0, 30.0, 122.0));
+        let readings = aggregator.flush();
+        assert!(!readings[0].tags[1].invalid);
+        assert_eq!(readings[0].f64(OES_PLUG_POWER), Some(20.0));
+        assert_eq!(readings[0].f64(OES_PLUG_VOLTAGE), Some(121.0));
+
+        // Valid values replace a leading invalid one, and a window without any stays invalid
+        aggregator.add(&invalid_power("a", 0.0));
+        aggregator.add(&reading("a", 0, 40.0, 120.0));
+        aggregator.add(&invalid_power("b", 0.0));
+        aggregator.add(&invalid_power("b", 5.0));
+        let readings = aggregator.flush();
+        assert!(!readings[0].tags[1].invalid);
+        assert_eq!(readings[0].f64(OES_PLUG_POWER), Some(40.0));
+        assert!(readings[1].tags[1].invalid);
+        assert_eq!(readings[1].f64(OES_PLUG_POWER), Some(5.0));
+    }
+}
@@ -0,0 +1,253 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{Codec, OESPlug};
+
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    fs, io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Datagram wrapped with a message counter and an HMAC-SHA256 tag.  The tag covers the counter
+/// (8 bytes, big endian) followed by the encoded payload.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub counter: u64,
+    pub payload: String,
+    pub hmac: String,
+}
+
+fn new_mac(key: &[u8], counter: u64, payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    mac.update(payload);
+    mac
+}
+
+pub fn seal(key: &[u8], counter: u64, payload: &[u8]) -> Envelope {
+    let tag = new_mac(key, counter, payload).finalize().into_bytes();
+    Envelope {
+        counter,
+        payload: hex::encode(payload),
+        hmac: hex::encode(tag),
+    }
+}
+
+/// Verify the envelope tag and return the payload
+pub fn verify(key: &[u8], envelope: &Envelope) -> Result<Vec<u8>, &'static str> {
+    let payload = hex::decode(&envelope.payload).map_err(|_e| "Invalid envelope payload")?;
+    let tag = hex::decode(&envelope.hmac).map_err(|_e| "Invalid envelope HMAC")?;
+    new_mac(key, envelope.counter, &payload)
+        .verify_slice(&tag)
+        .map_err(|_e| "HMAC verification failed")?;
+    Ok(payload)
+}
+
+/// Counter for outgoing envelopes.  It is seeded from the clock so that it keeps increasing
+/// across adapter restarts.
+pub fn next_counter() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let previous = COUNTER
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |c| {
+            Some(std::cmp::max(c + 1, now))
+        })
+        .unwrap();
+    std::cmp::max(previous + 1, now)
+}
+
+/// How often accepted counters are written to disk
+const REPLAY_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Highest counter accepted from each plug, used to reject replayed envelopes.  Messages that
+/// travel on different paths, such as heartbeats and command replies, each need their own guard
+/// since they can arrive out of order.
+#[derive(Debug)]
+pub struct ReplayGuard {
+    last: HashMap<String, u64>,
+    path: Option<PathBuf>,
+    last_saved: Instant,
+    dirty: bool,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        ReplayGuard {
+            last: HashMap::new(),
+            path: None,
+            last_saved: Instant::now(),
+            dirty: false,
+        }
+    }
+}
+
+impl ReplayGuard {
+    /// Create a guard that keeps its counters in `path`, loading them when the file exists, so
+    /// that envelopes seen before a restart are still rejected
+    pub fn open(path: Option<PathBuf>) -> io::Result<ReplayGuard> {
+        let last = match &path {
+            Some(path) if path.exists() => serde_json::from_str(&fs::read_to_string(path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            _ => HashMap::new(),
+        };
+        Ok(ReplayGuard {
+            last,
+            path,
+            ..Default::default()
+        })
+    }
+
+    /// Accept `counter` if it is newer than anything seen from `peer`
+    pub fn accept(&mut self, peer: &str, counter: u64) -> bool {
+        match self.last.get(peer) {
+            Some(last) if counter <= *last => false,
+            _ => {
+                self.last.insert(peer.to_string(), counter);
+                self.dirty = true;
+                true
+            }
+        }
+    }
+
+    /// Write the counters to disk if they changed and were not saved recently
+    pub fn save_if_due(&mut self) {
+        if self.dirty && self.last_saved.elapsed() >= REPLAY_SAVE_INTERVAL {
+            if let Err(e) = self.save() {
+                log::error!("Unable to save replay counters: {}", e);
+            }
+        }
+    }
+
+    /// Write the counters to disk
+    pub fn save(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let json = serde_json::to_string_pretty(&self.last)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            // Write a temporary file first so a crash never leaves a truncated file behind
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, json)?;
+            fs::rename(&tmp, path)?;
+        }
+        self.last_saved = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Why an incoming datagram was not accepted
+#[derive(Debug, Clone, PartialEq)]
+pub enum DatagramError {
+    Decode(&'static str),
+    Auth(&'static str),
+}
+
+impl Display for DatagramError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            DatagramError::Decode(e) => write!(f, "{}", e),
+            DatagramError::Auth(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl OESPlug {
+    /// Whether datagrams to and from this plug are authenticated
+    pub fn is_authenticated(&self) -> bool {
+        self.hmac_key.is_some()
+    }
+
+    /// Encode a datagram for this plug, sealing it when an HMAC key is configured
+    pub fn encode_datagram<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, &'static str> {
+        let payload = self.codec.encode(value)?;
+        match &self.hmac_key {
+            Some(key) => self
+                .codec
+                .encode(&seal(key.as_bytes(), next_counter(), &payload)),
+            None => Ok(payload),
+        }
+    }
+
+    /// Decode a datagram from this plug, verifying and replay-checking it when an HMAC key is
+    /// configured
+    pub fn decode_datagram<T: DeserializeOwned>(
+        &self,
+        buf: &[u8],
+        replay_guard: &Mutex<ReplayGuard>,
+    ) -> Result<T, DatagramError> {
+        let payload = self.open_datagram(buf, replay_guard)?;
+        self.codec
+            .decode::<T>(&payload)
+            .map_err(DatagramError::Decode)
+    }
+
+    /// Payload of a datagram from this plug, taken out of its authentication envelope when an
+    /// HMAC key is configured
+    pub fn open_datagram(
+        &self,
+        buf: &[u8],
+        replay_guard: &Mutex<ReplayGuard>,
+    ) -> Result<Vec<u8>, DatagramError> {
+        match &self.hmac_key {
+            Some(key) => {
+                let envelope = self
+                    .codec
+                    .decode::<Envelope>(buf)
+                    .map_err(|_e| DatagramError::Auth("Missing authentication envelope"))?;
+                let payload = verify(key.as_bytes(), &envelope).map_err(DatagramError::Auth)?;
+                if !replay_guard
+                    .lock()
+                    .unwrap()
+                    .accept(&self.mac_address, envelope.counter)
+                {
+                    return Err(DatagramError::Auth("Replayed envelope"));
+                }
+                Ok(payload)
+            }
+            None => Ok(buf.to_vec()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_survive_restart() {
+        let path = std::env::temp_dir().join(format!("oes-replay-{}.json", std::process::id()));
+
+        let mut guard = ReplayGuard::open(Some(path.clone())).unwrap();
+        assert!(guard.accept("80c955645cd4", 10));
+        assert!(!guard.accept("80c955645cd4", 10));
+        assert!(guard.accept("80c955645cd5", 5));
+        // Saving waits for the save interval
+        guard.save_if_due();
+        assert!(!path.exists());
+        guard.save().unwrap();
+
+        let mut guard = ReplayGuard::open(Some(path.clone())).unwrap();
+        assert!(!guard.accept("80c955645cd4", 10));
+        assert!(!guard.accept("80c955645cd5", 4));
+        assert!(guard.accept("80c955645cd4", 11));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
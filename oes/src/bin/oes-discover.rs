@@ -0,0 +1,72 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use oes::{discover, to_stack_configuration, CodecType, DiscoveryOptions};
+use std::{env, fs, net::SocketAddr, time::Duration};
+
+const USAGE: &str = "Usage: oes-discover --plug-port 8556 [--listen 0.0.0.0:8555] [--seconds 30] [--probe 255.255.255.255:8556] [--codec json|cbor|msgpack] [--output stack.yaml]";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    pretty_env_logger::init();
+
+    let mut options = DiscoveryOptions {
+        listen: "0.0.0.0:8555".parse::<SocketAddr>()?,
+        duration: Duration::from_secs(30),
+        probe: None,
+        codec: CodecType::Json,
+    };
+    let mut plug_port = None;
+    let mut output = None;
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    for pair in args.chunks(2) {
+        let value = match pair.get(1) {
+            Some(value) => value,
+            None => {
+                eprintln!("{}", USAGE);
+                return Ok(());
+            }
+        };
+        match pair[0].as_str() {
+            "--listen" => options.listen = value.parse()?,
+            "--seconds" => options.duration = Duration::from_secs(value.parse()?),
+            "--probe" => options.probe = Some(value.parse()?),
+            "--plug-port" => plug_port = Some(value.parse()?),
+            "--codec" => options.codec = serde_yaml::from_str(value)?,
+            "--output" => output = Some(value.clone()),
+            _ => {
+                eprintln!("{}", USAGE);
+                return Ok(());
+            }
+        }
+    }
+
+    // Heartbeats can come from any port, so the request port has to be given
+    let plug_port = match plug_port {
+        Some(port) => port,
+        None => {
+            eprintln!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    // Progress goes to stderr so the configuration can be redirected from stdout
+    eprintln!(
+        "Listening on {} for {:?}...",
+        options.listen, options.duration
+    );
+    let plugs = discover(&options).await?;
+    eprintln!("Found {} plug(s)", plugs.len());
+
+    let config = to_stack_configuration(&plugs, &options.listen, plug_port, options.codec);
+    let yaml = serde_yaml::to_string(&config)?;
+
+    match output {
+        Some(path) => fs::write(&path, yaml)?,
+        None => print!("{}", yaml),
+    }
+
+    Ok(())
+}
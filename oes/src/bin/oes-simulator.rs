@@ -0,0 +1,36 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use oes::{simulate, SimulatorConfiguration};
+use std::{env, fs};
+
+const USAGE: &str = "Usage: oes-simulator [--config simulator.yaml]";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    pretty_env_logger::init();
+
+    let mut path = "simulator.yaml".to_string();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    for pair in args.chunks(2) {
+        match (pair[0].as_str(), pair.get(1)) {
+            ("--config", Some(value)) => path = value.clone(),
+            _ => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+        }
+    }
+
+    let config: SimulatorConfiguration = serde_yaml::from_str(&fs::read_to_string(&path)?)?;
+    println!(
+        "Simulating {} plug(s), sending heartbeats to {}",
+        config.plugs.len(),
+        config.adapter
+    );
+    simulate(config).await?;
+
+    Ok(())
+}
@@ -0,0 +1,354 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{tags::convert, DatagramError, OESPlug, Reading, ReplayGuard, Tag, TagType};
+
+/// Byte order of a binary field
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum Endianness {
+    #[default]
+    #[serde(rename = "big")]
+    Big,
+    #[serde(rename = "little")]
+    Little,
+}
+
+/// Encoding of a binary field
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldType {
+    /// Two's complement integer of 1 to 8 bytes
+    #[serde(rename = "int")]
+    Int,
+    /// Unsigned integer of 1 to 8 bytes
+    #[serde(rename = "uint")]
+    UInt,
+    /// IEEE 754 float of 4 or 8 bytes
+    #[serde(rename = "float")]
+    Float,
+    /// UTF-8 text, padded with NUL bytes
+    #[serde(rename = "string")]
+    String,
+}
+
+/// Location and encoding of a tag in a fixed-layout binary frame
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BinaryField {
+    #[serde(rename = "name")]
+    pub name: String,
+
+    /// Position of the first byte in the frame
+    #[serde(rename = "offset")]
+    pub offset: usize,
+
+    /// Size in bytes
+    #[serde(rename = "width")]
+    pub width: usize,
+
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+
+    #[serde(rename = "endianness", default)]
+    pub endianness: Endianness,
+
+    /// Factor applied to numeric values, e.g. `0.1` for a value sent in tenths
+    #[serde(rename = "scale")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<f64>,
+
+    #[serde(rename = "tag-type", default)]
+    pub tag_type: TagType,
+
+    /// Raw values of an enum tag and the enum values they map to
+    #[serde(rename = "values", default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub values: BTreeMap<String, i32>,
+}
+
+impl BinaryField {
+    /// Decode the field from a frame.  Fields outside the frame or with an unsupported width are
+    /// skipped.
+    pub fn decode(&self, frame: &[u8]) -> Option<Tag> {
+        let end = self.offset.checked_add(self.width);
+        let bytes = match end.and_then(|end| frame.get(self.offset..end)) {
+            Some(bytes) => bytes,
+            None => {
+                log::warn!(
+                    "{} at offset {} with width {} is outside the {} byte frame",
+                    self.name,
+                    self.offset,
+                    self.width,
+                    frame.len()
+                );
+                return None;
+            }
+        };
+
+        let raw = match self.raw_value(bytes) {
+            Some(raw) => raw,
+            None => {
+                log::warn!(
+                    "Unsupported width {} for {:?} field {}",
+                    self.width,
+                    self.field_type,
+                    self.name
+                );
+                return None;
+            }
+        };
+
+        match convert(&raw, self.tag_type, &self.values) {
+            Some(value) => Some(Tag::new(&self.name, value)),
+            None => {
+                log::warn!(
+                    "Unable to convert {} to {:?} for {}",
+                    raw,
+                    self.tag_type,
+                    self.name
+                );
+                None
+            }
+        }
+    }
+
+    fn raw_value(&self, bytes: &[u8]) -> Option<Value> {
+        let value = match self.field_type {
+            FieldType::Int | FieldType::UInt => {
+                if bytes.is_empty() || bytes.len() > 8 {
+                    return None;
+                }
+                let bits = self.to_u64(bytes);
+                let unused = 64 - 8 * bytes.len() as u32;
+                match self.field_type {
+                    // Sign extend from the field width
+                    FieldType::Int => Value::from(((bits << unused) as i64) >> unused),
+                    _ => Value::from(bits),
+                }
+            }
+            FieldType::Float => {
+                let v = match bytes.len() {
+                    4 => f32::from_bits(self.to_u64(bytes) as u32) as f64,
+                    8 => f64::from_bits(self.to_u64(bytes)),
+                    _ => return None,
+                };
+                Value::from(v)
+            }
+            FieldType::String => {
+                let text = String::from_utf8_lossy(bytes);
+                return Some(Value::from(text.trim_end_matches('\0')));
+            }
+        };
+
+        match (self.scale, value.as_f64()) {
+            (Some(scale), Some(v)) => Some(Value::from(v * scale)),
+            _ => Some(value),
+        }
+    }
+
+    fn to_u64(&self, bytes: &[u8]) -> u64 {
+        let fold = |acc: u64, b: &u8| (acc << 8) | *b as u64;
+        match self.endianness {
+            Endianness::Big => bytes.iter().fold(0, fold),
+            Endianness::Little => bytes.iter().rev().fold(0, fold),
+        }
+    }
+}
+
+/// Decode every field of a binary frame
+pub fn decode_binary(layout: &[BinaryField], frame: &[u8]) -> Vec<Tag> {
+    layout.iter().filter_map(|f| f.decode(frame)).collect()
+}
+
+impl OESPlug {
+    /// Whether this device sends fixed-layout binary frames instead of encoded messages
+    pub fn is_binary(&self) -> bool {
+        !self.binary_layout.is_empty()
+    }
+
+    /// Decode a binary frame from this device into a reading
+    pub fn decode_frame(
+        &self,
+        buf: &[u8],
+        replay_guard: &Mutex<ReplayGuard>,
+    ) -> Result<Reading, DatagramError> {
+        let frame = self.open_datagram(buf, replay_guard)?;
+        let tags = decode_binary(&self.binary_layout, &frame);
+        if tags.is_empty() {
+            return Err(DatagramError::Decode("No fields decoded from binary frame"));
+        }
+        Ok(Reading {
+            mac_address: self.mac_address.clone(),
+            tags,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TagValue;
+
+    fn layout() -> Vec<BinaryField> {
+        serde_yaml::from_str(
+            r#"
+- name: Legacy.Power
+  offset: 0
+  width: 4
+  type: float
+- name: Legacy.Voltage
+  offset: 4
+  width: 2
+  type: uint
+  endianness: little
+  scale: 0.1
+- name: Legacy.Temperature
+  offset: 6
+  width: 2
+  type: int
+- name: Legacy.Relay
+  offset: 8
+  width: 1
+  type: uint
+  tag-type: enum
+  values:
+    "0": 0
+    "255": 1
+- name: Legacy.Alarm
+  offset: 9
+  width: 1
+  type: uint
+  tag-type: bool
+- name: Legacy.Name
+  offset: 10
+  width: 6
+  type: string
+  tag-type: string
+"#,
+        )
+        .unwrap()
+    }
+
+    fn frame() -> Vec<u8> {
+        let mut frame = vec![];
+        frame.extend_from_slice(&151.5f32.to_be_bytes());
+        frame.extend_from_slice(&1198u16.to_le_bytes());
+        frame.extend_from_slice(&(-12i16).to_be_bytes());
+        frame.push(255);
+        frame.push(0);
+        frame.extend_from_slice(b"pump\0\0");
+        frame
+    }
+
+    #[test]
+    fn decodes_fields() {
+        let tags = decode_binary(&layout(), &frame());
+        assert_eq!(
+            tags,
+            vec![
+                Tag::new("Legacy.Power", TagValue::F64(151.5)),
+                Tag::new("Legacy.Voltage", TagValue::F64(1198.0 * 0.1)),
+                Tag::new("Legacy.Temperature", TagValue::F64(-12.0)),
+                Tag::new("Legacy.Relay", TagValue::Enum(1)),
+                Tag::new("Legacy.Alarm", TagValue::Bool(false)),
+                Tag::new("Legacy.Name", TagValue::String("pump".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_widths_and_byte_orders() {
+        let field = |field_type, width, endianness| BinaryField {
+            name: "X".to_string(),
+            offset: 1,
+            width,
+            field_type,
+            endianness,
+            scale: None,
+            tag_type: TagType::F64,
+            values: BTreeMap::new(),
+        };
+        let frame = [0xaa, 0xff, 0xff, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let value = |f: BinaryField| f.decode(&frame).map(|t| t.value);
+
+        assert_eq!(
+            value(field(FieldType::Int, 3, Endianness::Big)),
+            Some(TagValue::F64(-2.0))
+        );
+        assert_eq!(
+            value(field(FieldType::UInt, 3, Endianness::Big)),
+            Some(TagValue::F64(16777214.0))
+        );
+        assert_eq!(
+            value(field(FieldType::Int, 3, Endianness::Little)),
+            Some(TagValue::F64(-65537.0))
+        );
+        assert_eq!(
+            value(field(FieldType::Float, 8, Endianness::Little)),
+            Some(TagValue::F64(f64::from_le_bytes([
+                0xff, 0xff, 0xfe, 0, 0, 0, 0, 0
+            ])))
+        );
+
+        // Out of range and unsupported widths are skipped
+        assert_eq!(value(field(FieldType::Int, 9, Endianness::Big)), None);
+        assert_eq!(value(field(FieldType::Float, 2, Endianness::Big)), None);
+        assert_eq!(value(field(FieldType::UInt, 0, Endianness::Big)), None);
+        assert_eq!(
+            value(field(FieldType::UInt, usize::MAX, Endianness::Big)),
+            None
+        );
+    }
+
+    #[test]
+    fn decodes_frames_from_configured_device() {
+        let plug = OESPlug {
+            mac_address: "legacy-1".to_string(),
+            binary_layout: layout(),
+            ..Default::default()
+        };
+        assert!(plug.is_binary());
+        let guard = Mutex::new(ReplayGuard::default());
+
+        let reading = plug.decode_frame(&frame(), &guard).unwrap();
+        assert_eq!(reading.mac_address, "legacy-1");
+        assert_eq!(reading.f64("Legacy.Power"), Some(151.5));
+
+        // A truncated frame still yields the fields it contains
+        let reading = plug.decode_frame(&frame()[..6], &guard).unwrap();
+        assert_eq!(reading.tags.len(), 2);
+        assert!(plug.decode_frame(&[1, 2], &guard).is_err());
+    }
+
+    #[test]
+    fn binary_devices_are_decoded_by_source_address() {
+        use crate::StackConfiguration;
+
+        let config = StackConfiguration {
+            uncontrollable_plugs: vec![OESPlug {
+                ip_address: "192.168.86.50".to_string(),
+                mac_address: "legacy-1".to_string(),
+                binary_layout: layout(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let guard = Mutex::new(ReplayGuard::default());
+
+        let legacy = "192.168.86.50:9000".parse().unwrap();
+        let readings = config.decode_readings(&frame(), &legacy, &guard).unwrap();
+        assert_eq!(readings.len(), 1);
+        let reading = &readings[0];
+        assert_eq!(reading.mac_address, "legacy-1");
+        assert_eq!(reading.tags.len(), 6);
+
+        // Frames from other senders are decoded as JSON
+        let other = "192.168.86.51:9000".parse().unwrap();
+        assert!(config.decode_readings(&frame(), &other, &guard).is_err());
+    }
+}
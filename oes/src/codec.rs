@@ -0,0 +1,107 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Encoding of the datagrams exchanged with a plug
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, &'static str>;
+    fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> Result<T, &'static str>;
+}
+
+/// UTF-8 JSON, used by the original plug firmware
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, &'static str> {
+        serde_json::to_vec(value).map_err(|_e| {
+            log::error!("{}", _e);
+            "Error serializing JSON"
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> Result<T, &'static str> {
+        let json = std::str::from_utf8(buf)
+            .map_err(|_e| "Error parse message")?
+            .trim_end();
+
+        serde_json::from_str::<T>(json).map_err(|_e| {
+            log::error!("{}", _e);
+            "Error deserializing JSON"
+        })
+    }
+}
+
+/// CBOR (RFC 8949)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, &'static str> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(value, &mut buf).map_err(|_e| {
+            log::error!("{}", _e);
+            "Error serializing CBOR"
+        })?;
+        Ok(buf)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> Result<T, &'static str> {
+        ciborium::de::from_reader(buf).map_err(|_e| {
+            log::error!("{}", _e);
+            "Error deserializing CBOR"
+        })
+    }
+}
+
+/// MessagePack, with structs encoded as maps so fields are matched by name
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, &'static str> {
+        rmp_serde::to_vec_named(value).map_err(|_e| {
+            log::error!("{}", _e);
+            "Error serializing MessagePack"
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> Result<T, &'static str> {
+        rmp_serde::from_slice(buf).map_err(|_e| {
+            log::error!("{}", _e);
+            "Error deserializing MessagePack"
+        })
+    }
+}
+
+/// Codec selected for a plug in the stack configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CodecType {
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    #[serde(rename = "cbor")]
+    Cbor,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl Codec for CodecType {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, &'static str> {
+        match self {
+            CodecType::Json => JsonCodec.encode(value),
+            CodecType::Cbor => CborCodec.encode(value),
+            CodecType::MessagePack => MessagePackCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> Result<T, &'static str> {
+        match self {
+            CodecType::Json => JsonCodec.decode(buf),
+            CodecType::Cbor => CborCodec.decode(buf),
+            CodecType::MessagePack => MessagePackCodec.decode(buf),
+        }
+    }
+}
@@ -2,9 +2,15 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{OESPlug, StackConfiguration};
+use crate::{resolve, DatagramStats, MulticastInterface, OESPlug, ReplayGuard, StackConfiguration};
 
-use std::{net::SocketAddr, sync::Arc};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+};
 use tokio::net::UdpSocket;
 
 /// OES plug connector
@@ -13,29 +19,238 @@ pub struct Connector {
     pub uncontrollable_plugs: Vec<OESPlug>,
     pub controllable_plugs: Vec<OESPlug>,
     pub sock: Arc<UdpSocket>,
+    pub stats: Arc<DatagramStats>,
+    /// Counters of heartbeats and status reports received on the adapter socket
+    pub heartbeat_guard: Arc<Mutex<ReplayGuard>>,
+    /// Counters of replies to commands
+    pub reply_guard: Arc<Mutex<ReplayGuard>>,
 }
 
 impl Connector {
     pub async fn connect(
         config: &StackConfiguration,
     ) -> Result<Connector, Box<dyn std::error::Error>> {
-        let address = format!("{}:{}", config.ip_address.clone(), config.port);
-        let sock = UdpSocket::bind(address.parse::<SocketAddr>()?).await?;
+        let address = resolve(&config.ip_address, config.port).await?;
+        let sock = UdpSocket::from_std(Self::bind_socket(&address, config)?)?;
         let sock = Arc::new(sock);
 
+        // Datagram sources are matched against plug hostnames without waiting for DNS
+        config.hosts.resolve(&config.hostnames()).await;
+        // Heartbeats and command replies arrive on different sockets, so each has its own counters
+        let replay_file = |name: &str| config.replay_dir.as_ref().map(|d| Path::new(d).join(name));
+        let heartbeat_guard = ReplayGuard::open(replay_file("heartbeats.json"))?;
+        let reply_guard = ReplayGuard::open(replay_file("replies.json"))?;
+
         let connector = Connector {
             uncontrollable_plugs: config.uncontrollable_plugs.clone(),
             controllable_plugs: config.controllable_plugs.clone(),
             sock: sock,
+            stats: Arc::new(DatagramStats::default()),
+            heartbeat_guard: Arc::new(Mutex::new(heartbeat_guard)),
+            reply_guard: Arc::new(Mutex::new(reply_guard)),
         };
 
         Ok(connector)
     }
 
+    /// Create the adapter socket with the socket options and multicast groups from `config`
+    fn bind_socket(
+        address: &SocketAddr,
+        config: &StackConfiguration,
+    ) -> io::Result<std::net::UdpSocket> {
+        let socket = Socket::new(
+            Domain::for_address(*address),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        socket.set_reuse_address(config.reuse_address)?;
+        if config.reuse_port {
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(unix))]
+            log::warn!("SO_REUSEPORT is not supported on this platform");
+        }
+        socket.set_broadcast(config.broadcast)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&(*address).into())?;
+
+        for group in &config.multicast_groups {
+            match group {
+                IpAddr::V4(group) if group.is_multicast() => {
+                    let interface = match config.multicast_interface {
+                        Some(MulticastInterface::Address(interface)) => interface,
+                        _ => Ipv4Addr::UNSPECIFIED,
+                    };
+                    socket.join_multicast_v4(group, &interface)?;
+                    log::info!("Joined multicast group {} on {}", group, interface);
+                }
+                IpAddr::V6(group) if group.is_multicast() => {
+                    let interface = match config.multicast_interface {
+                        Some(MulticastInterface::Index(index)) => index,
+                        _ => 0,
+                    };
+                    socket.join_multicast_v6(group, interface)?;
+                    log::info!(
+                        "Joined multicast group {} on interface {}",
+                        group,
+                        interface
+                    );
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} is not a multicast address", group),
+                    ))
+                }
+            }
+        }
+
+        Ok(socket.into())
+    }
+
     pub async fn bind_any() -> Option<Arc<UdpSocket>> {
         match UdpSocket::bind("0.0.0.0:0".parse::<SocketAddr>().unwrap()).await {
             Ok(sock) => Some(Arc::new(sock)),
             Err(_e) => None,
         }
     }
+
+    /// Bind to any local address of the same family as `remote`
+    pub async fn bind_any_for(remote: &SocketAddr) -> Option<Arc<UdpSocket>> {
+        let local = match remote {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        match UdpSocket::bind(local.parse::<SocketAddr>().unwrap()).await {
+            Ok(sock) => Some(Arc::new(sock)),
+            Err(_e) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(ip_address: &str, port: u32) -> StackConfiguration {
+        StackConfiguration {
+            ip_address: ip_address.to_string(),
+            port,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn adapters_share_port_with_reuse() {
+        let mut config = config("127.0.0.1", 0);
+        config.reuse_address = true;
+        config.reuse_port = true;
+        let first = Connector::connect(&config).await.unwrap();
+
+        config.port = first.sock.local_addr().unwrap().port() as u32;
+        let second = Connector::connect(&config).await.unwrap();
+        assert_eq!(
+            first.sock.local_addr().unwrap(),
+            second.sock.local_addr().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn port_is_exclusive_without_reuse() {
+        let first = Connector::connect(&config("127.0.0.1", 0)).await.unwrap();
+        let port = first.sock.local_addr().unwrap().port() as u32;
+        assert!(Connector::connect(&config("127.0.0.1", port))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn enables_broadcast() {
+        let mut config = config("0.0.0.0", 0);
+        assert!(!Connector::connect(&config)
+            .await
+            .unwrap()
+            .sock
+            .broadcast()
+            .unwrap());
+        config.broadcast = true;
+        assert!(Connector::connect(&config)
+            .await
+            .unwrap()
+            .sock
+            .broadcast()
+            .unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs multicast loopback on the host, which containers and CI runners often lack"]
+    async fn receives_multicast_heartbeats() {
+        let group: Ipv4Addr = "239.255.85.56".parse().unwrap();
+        let mut config = config("0.0.0.0", 0);
+        config.multicast_groups = vec![IpAddr::V4(group)];
+        config.multicast_interface = Some(MulticastInterface::Address(Ipv4Addr::LOCALHOST));
+        let connector = Connector::connect(&config).await.unwrap();
+        let port = connector.sock.local_addr().unwrap().port();
+
+        let sender = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        sender.set_multicast_if_v4(&Ipv4Addr::LOCALHOST).unwrap();
+        sender
+            .send_to(
+                b"heartbeat",
+                &SocketAddr::new(IpAddr::V4(group), port).into(),
+            )
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        let len = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            connector.sock.recv(&mut buf),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(&buf[..len], b"heartbeat");
+    }
+
+    #[tokio::test]
+    async fn rejects_unicast_groups() {
+        let mut config = config("0.0.0.0", 0);
+        config.multicast_groups = vec!["192.168.87.21".parse().unwrap()];
+        assert!(Connector::connect(&config).await.is_err());
+    }
+
+    #[test]
+    fn parses_multicast_options() {
+        let config: StackConfiguration = serde_yaml::from_str(
+            r#"
+adapter-ip-address: 0.0.0.0
+adapter-port: 8556
+uncontrollable-plugs: []
+controllable-plugs: []
+multicast-groups: [239.255.85.56, "ff15::8556"]
+multicast-interface: 192.168.87.1
+reuse-address: true
+reuse-port: true
+broadcast: true
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.multicast_groups.len(), 2);
+        assert!(config.multicast_groups[1].is_ipv6());
+        assert_eq!(
+            config.multicast_interface,
+            Some(MulticastInterface::Address("192.168.87.1".parse().unwrap()))
+        );
+        assert!(config.reuse_address && config.reuse_port && config.broadcast);
+
+        let config: StackConfiguration = serde_yaml::from_str(
+            "adapter-ip-address: '::'\nadapter-port: 8556\nuncontrollable-plugs: []\ncontrollable-plugs: []\nmulticast-interface: 2\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.multicast_interface,
+            Some(MulticastInterface::Index(2))
+        );
+        assert!(config.multicast_groups.is_empty() && !config.broadcast);
+    }
 }
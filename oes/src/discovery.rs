@@ -0,0 +1,197 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{Codec, CodecType, Data, OESOutlet, OESPlug, OpRequest, StackConfiguration};
+
+use std::{collections::BTreeMap, io, net::SocketAddr, time::Duration};
+use tokio::{
+    net::UdpSocket,
+    time::{interval, timeout_at, Instant},
+};
+use uuid::Uuid;
+
+/// How often the probe is repeated while discovering
+const PROBE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Settings for a discovery run
+#[derive(Debug, Clone)]
+pub struct DiscoveryOptions {
+    /// Address to listen on for heartbeats, usually the adapter address and port
+    pub listen: SocketAddr,
+    /// How long to collect heartbeats
+    pub duration: Duration,
+    /// Broadcast address to send status requests to, if plugs should be probed
+    pub probe: Option<SocketAddr>,
+    /// Codec used for decoding heartbeats and encoding probes
+    pub codec: CodecType,
+}
+
+/// A plug seen during discovery
+#[derive(Debug, Clone)]
+pub struct DiscoveredPlug {
+    pub data: Data,
+    pub source: SocketAddr,
+}
+
+/// Listen for heartbeats (and optionally probe) for the configured duration.  Plugs are returned
+/// ordered by MAC address, with the last heartbeat received from each.
+pub async fn discover(options: &DiscoveryOptions) -> io::Result<Vec<DiscoveredPlug>> {
+    let sock = UdpSocket::bind(options.listen).await?;
+    sock.set_broadcast(options.probe.is_some())?;
+
+    let probe = match options.codec.encode(&OpRequest::SR) {
+        Ok(probe) => probe,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    };
+
+    let deadline = Instant::now() + options.duration;
+    let mut ticker = interval(PROBE_PERIOD);
+    let mut plugs = BTreeMap::new();
+    let mut buf = [0u8; 1024];
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick(), if options.probe.is_some() => {
+                let address = options.probe.unwrap();
+                if let Err(e) = sock.send_to(&probe, address).await {
+                    log::error!("Failed to send probe to {}: {}", address, e);
+                }
+            }
+            received = timeout_at(deadline, sock.recv_from(&mut buf)) => {
+                let (len, source) = match received {
+                    Ok(received) => received?,
+                    Err(_) => break,
+                };
+                match options.codec.decode::<Data>(&buf[0..len]) {
+                    Ok(data) if !data.mac_address.is_empty() => {
+                        if !plugs.contains_key(&data.mac_address) {
+                            log::info!("Discovered {} ({}) at {}", data.mac_address, data.name, source);
+                        }
+                        plugs.insert(data.mac_address.clone(), DiscoveredPlug { data, source });
+                    }
+                    Ok(_) => log::debug!("Ignoring datagram without MAC address from {}", source),
+                    Err(e) => log::debug!("Ignoring datagram from {}: {}", source, e),
+                }
+            }
+        }
+    }
+
+    Ok(plugs.into_values().collect())
+}
+
+/// Stable mRID for a plug, derived from its MAC address so that repeated discoveries agree
+pub fn mrid_from_mac(mac_address: &str) -> String {
+    let mac = mac_address.to_lowercase().replace([':', '-'], "");
+    Uuid::new_v5(&Uuid::NAMESPACE_OID, format!("oes-plug:{}", mac).as_bytes())
+        .hyphenated()
+        .to_string()
+}
+
+/// Build a stack configuration listing every discovered plug as both uncontrollable and
+/// controllable.  `plug_port` is the port plugs receive requests on; heartbeats do not reveal it,
+/// since plugs may send them from any port.
+pub fn to_stack_configuration(
+    plugs: &[DiscoveredPlug],
+    listen: &SocketAddr,
+    plug_port: u32,
+    codec: CodecType,
+) -> StackConfiguration {
+    let plugs: Vec<OESPlug> = plugs
+        .iter()
+        .map(|p| OESPlug {
+            ip_address: if p.data.ip_address.is_empty() {
+                p.source.ip().to_string()
+            } else {
+                p.data.ip_address.clone()
+            },
+            port: plug_port,
+            mac_address: p.data.mac_address.clone(),
+            mrid: mrid_from_mac(&p.data.mac_address),
+            codec,
+            outlets: p
+                .data
+                .outlets
+                .iter()
+                .map(|o| OESOutlet {
+                    index: o.index,
+                    mrid: mrid_from_mac(&format!("{}/{}", p.data.mac_address, o.index)),
+                })
+                .collect(),
+            ..Default::default()
+        })
+        .collect();
+
+    StackConfiguration {
+        ip_address: listen.ip().to_string(),
+        port: listen.port() as u32,
+        uncontrollable_plugs: plugs.clone(),
+        controllable_plugs: plugs,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Outlet;
+
+    fn plug(ip_address: &str, mac_address: &str, source: &str) -> DiscoveredPlug {
+        DiscoveredPlug {
+            data: Data {
+                ip_address: ip_address.to_string(),
+                mac_address: mac_address.to_string(),
+                ..Default::default()
+            },
+            source: source.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn mrids_are_derived_from_mac() {
+        let mrid = mrid_from_mac("80c955645cd4");
+        assert_eq!(mrid, "08e782ad-fa4b-53e0-b6d3-3204acebc5b4");
+        assert_eq!(mrid_from_mac("80:C9:55:64:5C:D4"), mrid);
+        assert_eq!(mrid_from_mac("80-c9-55-64-5c-d4"), mrid);
+        assert_ne!(mrid_from_mac("80c955645cd5"), mrid);
+    }
+
+    #[test]
+    fn stack_configuration() {
+        let mut strip = plug("", "80c955645cd4", "192.168.1.20:49152");
+        strip.data.outlets = vec![Outlet {
+            index: 2,
+            ..Default::default()
+        }];
+        let plugs = vec![
+            strip,
+            plug("192.168.1.21", "80c955645cd5", "192.168.1.99:8556"),
+        ];
+        let listen = "0.0.0.0:8555".parse().unwrap();
+        let config = to_stack_configuration(&plugs, &listen, 8556, CodecType::Cbor);
+
+        assert_eq!(config.ip_address, "0.0.0.0");
+        assert_eq!(config.port, 8555);
+        assert_eq!(config.uncontrollable_plugs, config.controllable_plugs);
+
+        let strip = &config.uncontrollable_plugs[0];
+        assert_eq!(strip.ip_address, "192.168.1.20");
+        assert_eq!(strip.port, 8556);
+        assert_eq!(strip.mac_address, "80c955645cd4");
+        assert_eq!(strip.mrid, "08e782ad-fa4b-53e0-b6d3-3204acebc5b4");
+        assert_eq!(strip.codec, CodecType::Cbor);
+        assert_eq!(
+            strip.outlets,
+            vec![OESOutlet {
+                index: 2,
+                mrid: "8155b8ac-efbe-520c-9132-b840b10d3c5d".to_string(),
+            }]
+        );
+
+        // The reported address wins over the source of the heartbeat
+        let plug = &config.uncontrollable_plugs[1];
+        assert_eq!(plug.ip_address, "192.168.1.21");
+        assert_eq!(plug.port, 8556);
+        assert!(plug.outlets.is_empty());
+    }
+}
@@ -0,0 +1,277 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How often accumulated totals are written to disk
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longest interval between samples that is integrated when no liveness timeout is configured
+const DEFAULT_MAX_GAP: Duration = Duration::from_secs(300);
+
+/// Energy accumulated by a plug, in watt-hours
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EnergyTotals {
+    /// Energy drawn by the load (positive power)
+    #[serde(rename = "demand-wh")]
+    pub demand_wh: f64,
+    /// Energy fed back through the plug (negative power)
+    #[serde(rename = "supply-wh")]
+    pub supply_wh: f64,
+}
+
+impl EnergyTotals {
+    /// Net energy
+    pub fn total_wh(&self) -> f64 {
+        self.demand_wh - self.supply_wh
+    }
+}
+
+/// Totals saved in `path`.  A corrupt file is renamed to `<path>.corrupt` and counting starts
+/// from zero, so the file is kept for inspection instead of being overwritten by the next save.
+fn load(path: &Path) -> io::Result<BTreeMap<String, EnergyTotals>> {
+    match serde_json::from_slice(&fs::read(path)?) {
+        Ok(totals) => Ok(totals),
+        Err(e) => {
+            let mut corrupt = path.as_os_str().to_owned();
+            corrupt.push(".corrupt");
+            fs::rename(path, &corrupt)?;
+            log::error!(
+                "Energy totals in {:?} are corrupt and were moved to {:?}, starting from zero: {}",
+                path,
+                corrupt,
+                e
+            );
+            Ok(BTreeMap::new())
+        }
+    }
+}
+
+struct Sample {
+    at: Instant,
+    power: f64,
+}
+
+/// Integrates plug power over time, keyed by MAC address, and keeps the totals on disk
+pub struct EnergyMeter {
+    path: Option<PathBuf>,
+    max_gap: Duration,
+    totals: BTreeMap<String, EnergyTotals>,
+    last: HashMap<String, Sample>,
+    last_saved: Instant,
+    dirty: bool,
+}
+
+impl EnergyMeter {
+    /// Create a meter, loading previous totals from `path` when it exists.  Intervals between
+    /// samples longer than `max_gap` (5 minutes when `None`) are not integrated since the power in
+    /// between is unknown.
+    pub fn open(path: Option<PathBuf>, max_gap: Option<Duration>) -> io::Result<EnergyMeter> {
+        let totals = match &path {
+            Some(path) if path.exists() => load(path)?,
+            _ => BTreeMap::new(),
+        };
+
+        Ok(EnergyMeter {
+            path,
+            max_gap: max_gap.unwrap_or(DEFAULT_MAX_GAP),
+            totals,
+            last: HashMap::new(),
+            last_saved: Instant::now(),
+            dirty: false,
+        })
+    }
+
+    /// Accumulate the energy since the plug's previous sample using the trapezoidal rule.  When
+    /// the power changes sign, the trapezoid is split where it crosses zero so that demand and
+    /// supply are both counted.
+    pub fn update(&mut self, mac_address: &str, power: f64, at: Instant) -> EnergyTotals {
+        let totals = self.totals.entry(mac_address.to_string()).or_default();
+
+        if let Some(last) = self.last.get(mac_address) {
+            let elapsed = at.saturating_duration_since(last.at);
+            if elapsed <= self.max_gap {
+                let hours = elapsed.as_secs_f64() / 3600.0;
+                let parts = if last.power * power < 0.0 {
+                    let crossing = hours * last.power.abs() / (last.power.abs() + power.abs());
+                    [
+                        last.power / 2.0 * crossing,
+                        power / 2.0 * (hours - crossing),
+                    ]
+                } else {
+                    [(last.power + power) / 2.0 * hours, 0.0]
+                };
+                for wh in parts {
+                    if wh >= 0.0 {
+                        totals.demand_wh += wh;
+                    } else {
+                        totals.supply_wh -= wh;
+                    }
+                }
+                self.dirty = true;
+            } else {
+                log::warn!(
+                    "No power samples from {} for {:?}, energy not accumulated",
+                    mac_address,
+                    elapsed
+                );
+            }
+        }
+
+        self.last
+            .insert(mac_address.to_string(), Sample { at, power });
+        *totals
+    }
+
+    /// Totals accumulated for a plug
+    pub fn totals(&self, mac_address: &str) -> Option<EnergyTotals> {
+        self.totals.get(mac_address).copied()
+    }
+
+    /// Write the totals to disk if they changed and were not saved recently
+    pub fn save_if_due(&mut self) {
+        if self.dirty && self.last_saved.elapsed() >= SAVE_INTERVAL {
+            if let Err(e) = self.save() {
+                log::error!("Unable to save energy totals: {}", e);
+            }
+        }
+    }
+
+    /// Write the totals to disk
+    pub fn save(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let json = serde_json::to_string_pretty(&self.totals)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            // Write a temporary file first so a crash never leaves a truncated file behind
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, json)?;
+            fs::rename(&tmp, path)?;
+        }
+        self.last_saved = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: &str = "80c955645cd4";
+
+    #[test]
+    fn integrates_power_over_time() {
+        let mut meter = EnergyMeter::open(None, Some(Duration::from_secs(3600))).unwrap();
+        let start = Instant::now();
+
+        assert_eq!(meter.update(MAC, 100.0, start).total_wh(), 0.0);
+        let totals = meter.update(MAC, 100.0, start + Duration::from_secs(1800));
+        assert!((totals.demand_wh - 50.0).abs() < 1e-9);
+
+        // Trapezoid between 100 W and 300 W over an hour
+        let totals = meter.update(MAC, 300.0, start + Duration::from_secs(5400));
+        assert!((totals.demand_wh - 250.0).abs() < 1e-9);
+        assert_eq!(totals.supply_wh, 0.0);
+    }
+
+    #[test]
+    fn negative_power_is_supply() {
+        let mut meter = EnergyMeter::open(None, Some(Duration::from_secs(3600))).unwrap();
+        let start = Instant::now();
+
+        meter.update(MAC, -60.0, start);
+        let totals = meter.update(MAC, -60.0, start + Duration::from_secs(3600));
+        assert!((totals.supply_wh - 60.0).abs() < 1e-9);
+        assert!((totals.total_wh() + 60.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn splits_at_zero_crossing() {
+        let mut meter = EnergyMeter::open(None, Some(Duration::from_secs(3600))).unwrap();
+        let start = Instant::now();
+
+        // Power falls linearly from 100 W to -300 W over an hour, crossing zero after 15 minutes
+        meter.update(MAC, 100.0, start);
+        let totals = meter.update(MAC, -300.0, start + Duration::from_secs(3600));
+        assert!((totals.demand_wh - 12.5).abs() < 1e-9);
+        assert!((totals.supply_wh - 112.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn skips_gaps_and_tracks_plugs_separately() {
+        let mut meter = EnergyMeter::open(None, Some(Duration::from_secs(60))).unwrap();
+        let start = Instant::now();
+
+        meter.update(MAC, 100.0, start);
+        meter.update("other", 100.0, start);
+        let totals = meter.update(MAC, 100.0, start + Duration::from_secs(3600));
+        assert_eq!(totals.total_wh(), 0.0);
+
+        let totals = meter.update(MAC, 100.0, start + Duration::from_secs(3636));
+        assert!((totals.demand_wh - 1.0).abs() < 1e-9);
+        assert_eq!(meter.totals("other"), Some(EnergyTotals::default()));
+        assert_eq!(meter.totals("unknown"), None);
+    }
+
+    #[test]
+    fn bounds_gaps_without_liveness_timeout() {
+        let mut meter = EnergyMeter::open(None, None).unwrap();
+        let start = Instant::now();
+
+        meter.update(MAC, 3600.0, start);
+        let totals = meter.update(MAC, 3600.0, start + DEFAULT_MAX_GAP);
+        assert!((totals.demand_wh - 300.0).abs() < 1e-9);
+
+        // A plug silent for longer than the default gap adds nothing
+        let later = start + DEFAULT_MAX_GAP * 2 + Duration::from_secs(1);
+        let totals = meter.update(MAC, 3600.0, later);
+        assert!((totals.demand_wh - 300.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn totals_survive_restart() {
+        let path = std::env::temp_dir().join(format!("oes-energy-{}.json", std::process::id()));
+        let start = Instant::now();
+
+        let mut meter = EnergyMeter::open(Some(path.clone()), None).unwrap();
+        meter.update(MAC, 1000.0, start);
+        meter.update(MAC, 1000.0, start + Duration::from_secs(36));
+        meter.save().unwrap();
+
+        // Accumulation continues from the saved totals with a fresh first sample
+        let mut meter = EnergyMeter::open(Some(path.clone()), None).unwrap();
+        assert!((meter.totals(MAC).unwrap().demand_wh - 10.0).abs() < 1e-9);
+        let later = Instant::now();
+        meter.update(MAC, 1000.0, later);
+        let totals = meter.update(MAC, 1000.0, later + Duration::from_secs(36));
+        assert!((totals.demand_wh - 20.0).abs() < 1e-9);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_corrupt_file() {
+        let path = std::env::temp_dir().join(format!("oes-corrupt-{}.json", std::process::id()));
+        let corrupt = path.with_extension("json.corrupt");
+        fs::write(&path, "{\"80c955645cd4\": {\"demand-wh\":").unwrap();
+
+        let mut meter = EnergyMeter::open(Some(path.clone()), None).unwrap();
+        assert_eq!(meter.totals(MAC), None);
+        assert!(fs::read_to_string(&corrupt).unwrap().starts_with("{"));
+
+        meter.update(MAC, 1000.0, Instant::now());
+        meter.save().unwrap();
+        assert!(path.exists() && corrupt.exists());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&corrupt).unwrap();
+    }
+}
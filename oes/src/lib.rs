@@ -2,12 +2,36 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod address;
+pub mod aggregate;
+pub mod auth;
+pub mod binary;
+pub mod codec;
 pub mod connector;
+pub mod discovery;
+pub mod energy;
 pub mod messages;
 pub mod opcode;
+pub mod report;
+#[cfg(feature = "simulator")]
+pub mod simulator;
 pub mod stack_config;
+pub mod stats;
+pub mod tags;
 
+pub use address::*;
+pub use aggregate::*;
+pub use auth::*;
+pub use binary::*;
+pub use codec::*;
 pub use connector::*;
+pub use discovery::*;
+pub use energy::*;
 pub use messages::*;
 pub use opcode::*;
+pub use report::*;
+#[cfg(feature = "simulator")]
+pub use simulator::*;
 pub use stack_config::*;
+pub use stats::*;
+pub use tags::*;
@@ -6,10 +6,31 @@ use std::fmt::{Display, Formatter};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{Codec, JsonCodec, OpCode};
+
 pub const OES_PLUG_STATUS: &str = "OES.Plug.Status";
 pub const OES_PLUG_POWER: &str = "OES.Plug.Power";
 pub const OES_PLUG_VOLTAGE: &str = "OES.Plug.Voltage";
 pub const OES_PLUG_CURRENT: &str = "OES.Plug.Current";
+pub const OES_PLUG_FREQUENCY: &str = "OES.Plug.Frequency";
+pub const OES_PLUG_POWER_FACTOR: &str = "OES.Plug.PowerFactor";
+pub const OES_PLUG_REACTIVE_POWER: &str = "OES.Plug.ReactivePower";
+pub const OES_PLUG_TEMPERATURE: &str = "OES.Plug.Temperature";
+pub const OES_PLUG_ENERGY: &str = "OES.Plug.Energy";
+pub const OES_PLUG_ENERGY_DEMAND: &str = "OES.Plug.Energy.Demand";
+pub const OES_PLUG_ENERGY_SUPPLY: &str = "OES.Plug.Energy.Supply";
+
+pub const OES_PLUG_STATUS_QUALITY: &str = "OES.Plug.Status.Quality";
+pub const OES_PLUG_POWER_QUALITY: &str = "OES.Plug.Power.Quality";
+pub const OES_PLUG_VOLTAGE_QUALITY: &str = "OES.Plug.Voltage.Quality";
+pub const OES_PLUG_CURRENT_QUALITY: &str = "OES.Plug.Current.Quality";
+pub const OES_PLUG_FREQUENCY_QUALITY: &str = "OES.Plug.Frequency.Quality";
+pub const OES_PLUG_POWER_FACTOR_QUALITY: &str = "OES.Plug.PowerFactor.Quality";
+pub const OES_PLUG_REACTIVE_POWER_QUALITY: &str = "OES.Plug.ReactivePower.Quality";
+pub const OES_PLUG_TEMPERATURE_QUALITY: &str = "OES.Plug.Temperature.Quality";
+pub const OES_PLUG_ENERGY_QUALITY: &str = "OES.Plug.Energy.Quality";
+pub const OES_PLUG_ENERGY_DEMAND_QUALITY: &str = "OES.Plug.Energy.Demand.Quality";
+pub const OES_PLUG_ENERGY_SUPPLY_QUALITY: &str = "OES.Plug.Energy.Supply.Quality";
 
 pub const OES_PLUG_COMMAND: &str = "OES.Plug.Command";
 
@@ -17,6 +38,8 @@ pub const OES_PLUG_COMMAND: &str = "OES.Plug.Command";
 pub enum PlugStatus {
     Off,
     On,
+    /// Relay state could not be determined; also used for states this adapter does not know
+    #[serde(other)]
     Unknown,
 }
 
@@ -26,6 +49,17 @@ impl Default for PlugStatus {
     }
 }
 
+impl PlugStatus {
+    /// Value of the `OES.Plug.Status` tag, mapped to a `DbPosKind` in the profile template
+    pub fn position(&self) -> i32 {
+        match self {
+            PlugStatus::Off => 0,
+            PlugStatus::On => 1,
+            PlugStatus::Unknown => 2,
+        }
+    }
+}
+
 impl Display for PlugStatus {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         let state = match self {
@@ -37,7 +71,7 @@ impl Display for PlugStatus {
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Data {
     pub ip_address: String,
     pub mac_address: String,
@@ -46,6 +80,85 @@ pub struct Data {
     pub power: f64,
     pub voltage: f64,
     pub current: f64,
+    /// Line frequency in Hz, reported by newer plugs only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power_factor: Option<f64>,
+    /// Reactive power in VAr
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reactive_power: Option<f64>,
+    /// Internal temperature in degrees Celsius
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// Device time of the measurement with its time quality bits, e.g.
+    /// `04/10/2021_19:34:06.582,[00000000]`; the receive time is used when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    /// Quality bit string of the measurement, e.g. `[0000000000000]`; good quality is assumed
+    /// when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+    /// Outlets of a power strip; the fields above then describe the strip as a whole
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outlets: Vec<Outlet>,
+}
+
+/// One relay of a multi-outlet plug.  Voltage and frequency are shared by all outlets.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Outlet {
+    /// Outlet number as printed on the strip
+    pub index: u32,
+    pub status: PlugStatus,
+    pub power: f64,
+    pub current: f64,
+}
+
+impl Data {
+    /// Look up an outlet by index
+    pub fn outlet(&self, index: u32) -> Option<&Outlet> {
+        self.outlets.iter().find(|o| o.index == index)
+    }
+
+    /// State of the plug relay, or of an outlet's relay; `Unknown` for outlets not reported
+    pub fn relay_status(&self, outlet: Option<u32>) -> PlugStatus {
+        match outlet {
+            Some(index) => self
+                .outlet(index)
+                .map(|o| o.status.clone())
+                .unwrap_or(PlugStatus::Unknown),
+            None => self.status.clone(),
+        }
+    }
+
+    /// Optional telemetry reported by the plug, as (tag, quality tag, value)
+    pub fn extended_telemetry(&self) -> Vec<(&'static str, &'static str, f64)> {
+        [
+            (
+                OES_PLUG_FREQUENCY,
+                OES_PLUG_FREQUENCY_QUALITY,
+                self.frequency,
+            ),
+            (
+                OES_PLUG_POWER_FACTOR,
+                OES_PLUG_POWER_FACTOR_QUALITY,
+                self.power_factor,
+            ),
+            (
+                OES_PLUG_REACTIVE_POWER,
+                OES_PLUG_REACTIVE_POWER_QUALITY,
+                self.reactive_power,
+            ),
+            (
+                OES_PLUG_TEMPERATURE,
+                OES_PLUG_TEMPERATURE_QUALITY,
+                self.temperature,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(tag, quality, value)| value.map(|v| (tag, quality, v)))
+        .collect()
+    }
 }
 
 impl Display for Data {
@@ -72,31 +185,367 @@ pub enum FC {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Request {
-    fc: FC,
+    pub fc: FC,
+    /// Outlet to switch on a multi-outlet plug; the whole plug is switched when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outlet: Option<u32>,
+}
+
+impl Request {
+    pub fn relay(on_off: bool) -> Request {
+        let fc = match on_off {
+            true => FC::On,
+            false => FC::Off,
+        };
+        Request { fc, outlet: None }
+    }
+
+    /// Address the request to one outlet of a multi-outlet plug
+    pub fn with_outlet(mut self, outlet: Option<u32>) -> Request {
+        self.outlet = outlet;
+        self
+    }
+
+    /// Relay requests are the plug's digital output operation
+    pub fn op_code(&self) -> OpCode {
+        OpCode::DO
+    }
+
+    /// State of the relay this request drives, as reported in a reply from the plug
+    pub fn reported_status(&self, reply: &Data) -> PlugStatus {
+        reply.relay_status(self.outlet)
+    }
+
+    /// Check whether a reply from the plug confirms this request.  The plug answers a relay
+    /// request with its current state, so the request is acknowledged when the state matches.
+    pub fn is_acknowledged_by(&self, reply: &Data) -> bool {
+        let status = self.reported_status(reply);
+        match self.fc {
+            FC::On => status == PlugStatus::On,
+            FC::Off => status == PlugStatus::Off,
+        }
+    }
 }
 
 pub fn set_relay_message(on_off: bool) -> String {
-    let request = match on_off {
-        true => Request { fc: FC::On },
-        false => Request { fc: FC::Off },
-    };
+    serde_json::to_string(&Request::relay(on_off)).unwrap()
+}
 
-    serde_json::to_string(&request).unwrap()
+/// Request for one of the plug operations in `OpCode` other than digital output, which is the
+/// relay `Request`.  On the wire the opcode is carried in the `op` field.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op")]
+pub enum OpRequest {
+    /// Status request; the plug answers with a full status report
+    SR,
+    /// Time configuration; set the plug clock to seconds since the UNIX epoch
+    TC { time: u64 },
+    /// Reset module; reboot the plug firmware
+    RM,
+    /// Telemetry timing; change the heartbeat interval
+    TT { interval_ms: u32 },
 }
 
-pub fn parse_message(buf: &[u8], len: usize) -> std::result::Result<Data, &'static str> {
-    use std::str::from_utf8;
+impl OpRequest {
+    pub fn op_code(&self) -> OpCode {
+        match self {
+            OpRequest::SR => OpCode::SR,
+            OpRequest::TC { .. } => OpCode::TC,
+            OpRequest::RM => OpCode::RM,
+            OpRequest::TT { .. } => OpCode::TT,
+        }
+    }
+}
+
+/// Reply from the plug to an `OpRequest`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op")]
+pub enum OpResponse {
+    /// Status report, same content as a heartbeat
+    SR(Data),
+    /// Plug clock after a time configuration request
+    TC { time: u64 },
+    /// Reset module acknowledged; the plug reboots after replying
+    RM,
+    /// Heartbeat interval now in use
+    TT { interval_ms: u32 },
+    /// The plug refused the request named in `request`
+    NAK { request: String, reason: String },
+}
+
+impl OpResponse {
+    /// Opcode of the request this response answers
+    pub fn op_code(&self) -> Option<OpCode> {
+        match self {
+            OpResponse::SR(_) => Some(OpCode::SR),
+            OpResponse::TC { .. } => Some(OpCode::TC),
+            OpResponse::RM => Some(OpCode::RM),
+            OpResponse::TT { .. } => Some(OpCode::TT),
+            OpResponse::NAK { request, .. } => request.parse::<OpCode>().ok(),
+        }
+    }
+}
+
+/// Reply from the plug to a relay `Request`: its new state, or an `OpResponse::NAK` when it
+/// refuses the request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RelayReply {
+    Op(OpResponse),
+    State(Data),
+}
+
+pub fn encode_request(request: &OpRequest) -> String {
+    serde_json::to_string(request).unwrap()
+}
+
+pub fn decode_request(buf: &[u8], len: usize) -> std::result::Result<OpRequest, &'static str> {
+    let json = std::str::from_utf8(&buf[0..len])
+        .map_err(|_e| "Error parse request")?
+        .trim_end();
+
+    serde_json::from_str::<OpRequest>(json).map_err(|_e| {
+        log::error!("{}", _e);
+        "Error deserializing request\n"
+    })
+}
+
+pub fn encode_response(response: &OpResponse) -> String {
+    serde_json::to_string(response).unwrap()
+}
 
-    let json = from_utf8(&buf[0..len])
-        .map_err(|_e| "Error parse message")?
+pub fn decode_response(buf: &[u8], len: usize) -> std::result::Result<OpResponse, &'static str> {
+    let json = std::str::from_utf8(&buf[0..len])
+        .map_err(|_e| "Error parse response")?
         .trim_end();
 
-    log::debug!("RECEIVED: {}", json);
+    serde_json::from_str::<OpResponse>(json).map_err(|_e| {
+        log::error!("{}", _e);
+        "Error deserializing response\n"
+    })
+}
+
+pub fn parse_message(buf: &[u8], len: usize) -> std::result::Result<Data, &'static str> {
+    parse_message_with(&JsonCodec, buf, len)
+}
+
+/// Parse a heartbeat or status report encoded with the plug's codec
+pub fn parse_message_with<C: Codec>(
+    codec: &C,
+    buf: &[u8],
+    len: usize,
+) -> std::result::Result<Data, &'static str> {
+    log::debug!("RECEIVED: {}", String::from_utf8_lossy(&buf[0..len]));
 
-    let data = serde_json::from_str::<Data>(json).map_err(|_e| {
+    codec.decode::<Data>(&buf[0..len]).map_err(|_e| {
         log::error!("{}", _e);
         "Error deserializing data\n"
-    })?;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> Data {
+        Data {
+            ip_address: "192.168.86.30".to_string(),
+            mac_address: "80c955645cd4".to_string(),
+            name: "plug".to_string(),
+            status: PlugStatus::On,
+            power: 12.5,
+            voltage: 120.1,
+            current: 0.104,
+            ..Default::default()
+        }
+    }
+
+    fn round_trip_request(request: OpRequest) {
+        let msg = encode_request(&request);
+        let decoded = decode_request(msg.as_bytes(), msg.len()).unwrap();
+        assert_eq!(decoded, request);
+        assert_eq!(decoded.op_code(), request.op_code());
+    }
+
+    fn round_trip_response(response: OpResponse) {
+        let msg = encode_response(&response);
+        let decoded = decode_response(msg.as_bytes(), msg.len()).unwrap();
+        assert_eq!(decoded, response);
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        round_trip_request(OpRequest::SR);
+        round_trip_request(OpRequest::TC { time: 1666000000 });
+        round_trip_request(OpRequest::RM);
+        round_trip_request(OpRequest::TT { interval_ms: 5000 });
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        round_trip_response(OpResponse::SR(data()));
+        round_trip_response(OpResponse::TC { time: 1666000000 });
+        round_trip_response(OpResponse::RM);
+        round_trip_response(OpResponse::TT { interval_ms: 5000 });
+        round_trip_response(OpResponse::NAK {
+            request: "TC".to_string(),
+            reason: "clock locked".to_string(),
+        });
+    }
+
+    #[test]
+    fn request_wire_format() {
+        assert_eq!(encode_request(&OpRequest::SR), r#"{"op":"SR"}"#);
+        // Digital output keeps the relay request format the firmware has always parsed
+        assert_eq!(set_relay_message(true), r#"{"fc":"On"}"#);
+        assert_eq!(Request::relay(true).op_code(), OpCode::DO);
+        assert!(decode_request(br#"{"op":"DO","fc":"On"}"#, 21).is_err());
+    }
+
+    #[test]
+    fn status_response_is_a_heartbeat() {
+        let msg = encode_response(&OpResponse::SR(data()));
+        assert_eq!(parse_message(msg.as_bytes(), msg.len()).unwrap(), data());
+    }
+
+    #[test]
+    fn nak_carries_op_code() {
+        let response = OpResponse::NAK {
+            request: "RM".to_string(),
+            reason: "busy".to_string(),
+        };
+        assert_eq!(response.op_code(), Some(OpCode::RM));
+    }
+
+    #[test]
+    fn relay_replies_are_states_or_naks() {
+        let reply: RelayReply =
+            serde_json::from_str(&serde_json::to_string(&data()).unwrap()).unwrap();
+        assert_eq!(reply, RelayReply::State(data()));
+
+        let reply: RelayReply =
+            serde_json::from_str(r#"{"op":"NAK","request":"DO","reason":"locked"}"#).unwrap();
+        assert_eq!(
+            reply,
+            RelayReply::Op(OpResponse::NAK {
+                request: "DO".to_string(),
+                reason: "locked".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn heartbeat_decodes_with_each_codec() {
+        use crate::CodecType;
+
+        for codec in [CodecType::Json, CodecType::Cbor, CodecType::MessagePack] {
+            let buf = codec.encode(&data()).unwrap();
+            assert_eq!(parse_message_with(&codec, &buf, buf.len()).unwrap(), data());
+
+            let request = OpRequest::TT { interval_ms: 5000 };
+            let buf = codec.encode(&request).unwrap();
+            assert_eq!(codec.decode::<OpRequest>(&buf).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn extended_telemetry_is_optional() {
+        use crate::CodecType;
+
+        let msg = r#"{"ip_address":"192.168.86.30","mac_address":"80c955645cd4","name":"plug","status":"On","power":12.5,"voltage":120.1,"current":0.104}"#;
+        let parsed = parse_message(msg.as_bytes(), msg.len()).unwrap();
+        assert_eq!(parsed, data());
+        assert!(parsed.extended_telemetry().is_empty());
+
+        let msg = r#"{"ip_address":"192.168.86.30","mac_address":"80c955645cd4","name":"plug","status":"On","power":12.5,"voltage":120.1,"current":0.104,"frequency":60.01,"reactive_power":-3.2}"#;
+        let parsed = parse_message(msg.as_bytes(), msg.len()).unwrap();
+        assert_eq!(parsed.frequency, Some(60.01));
+        assert_eq!(parsed.power_factor, None);
+        assert_eq!(
+            parsed.extended_telemetry(),
+            vec![
+                (OES_PLUG_FREQUENCY, OES_PLUG_FREQUENCY_QUALITY, 60.01),
+                (
+                    OES_PLUG_REACTIVE_POWER,
+                    OES_PLUG_REACTIVE_POWER_QUALITY,
+                    -3.2
+                ),
+            ]
+        );
 
-    Ok(data)
+        let extended = Data {
+            power_factor: Some(0.97),
+            temperature: Some(41.5),
+            ..data()
+        };
+        for codec in [CodecType::Json, CodecType::Cbor, CodecType::MessagePack] {
+            let buf = codec.encode(&extended).unwrap();
+            assert_eq!(
+                parse_message_with(&codec, &buf, buf.len()).unwrap(),
+                extended
+            );
+        }
+        assert!(!serde_json::to_string(&data())
+            .unwrap()
+            .contains("frequency"));
+    }
+
+    #[test]
+    fn unrecognised_status_is_unknown() {
+        let msg = r#"{"ip_address":"192.168.86.30","mac_address":"80c955645cd4","name":"plug","status":"Fault","power":0.0,"voltage":120.1,"current":0.0}"#;
+        let parsed = parse_message(msg.as_bytes(), msg.len()).unwrap();
+        assert_eq!(parsed.status, PlugStatus::Unknown);
+
+        // Tag values are fixed per variant, not by declaration order
+        assert_eq!(PlugStatus::Off.position(), 0);
+        assert_eq!(PlugStatus::On.position(), 1);
+        assert_eq!(PlugStatus::Unknown.position(), 2);
+    }
+
+    #[test]
+    fn outlet_requests() {
+        let strip = Data {
+            outlets: vec![
+                Outlet {
+                    index: 1,
+                    status: PlugStatus::On,
+                    power: 40.0,
+                    current: 0.33,
+                },
+                Outlet {
+                    index: 2,
+                    status: PlugStatus::Off,
+                    ..Default::default()
+                },
+            ],
+            ..data()
+        };
+        let msg = serde_json::to_string(&strip).unwrap();
+        assert!(msg.contains(r#""outlets":[{"index":1,"status":"On""#));
+        assert_eq!(parse_message(msg.as_bytes(), msg.len()).unwrap(), strip);
+        assert!(!serde_json::to_string(&data()).unwrap().contains("outlets"));
+
+        let request = Request::relay(false).with_outlet(Some(2));
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"fc":"Off","outlet":2}"#
+        );
+        assert_eq!(set_relay_message(true), r#"{"fc":"On"}"#);
+
+        // Each outlet is acknowledged by its own state
+        assert!(request.is_acknowledged_by(&strip));
+        assert!(!Request::relay(true)
+            .with_outlet(Some(2))
+            .is_acknowledged_by(&strip));
+        assert!(!Request::relay(true)
+            .with_outlet(Some(3))
+            .is_acknowledged_by(&strip));
+        assert!(Request::relay(true).is_acknowledged_by(&strip));
+    }
+
+    #[test]
+    fn invalid_request_is_rejected() {
+        let msg = r#"{"op":"XX"}"#;
+        assert!(decode_request(msg.as_bytes(), msg.len()).is_err());
+    }
 }
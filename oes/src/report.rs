@@ -0,0 +1,242 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{Tag, TagValue};
+
+/// Change a numeric tag must exceed to be reported.  When both limits are set, the change must
+/// exceed both, so `absolute` keeps values near zero from chattering.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Deadband {
+    #[serde(rename = "tag")]
+    pub tag: String,
+
+    #[serde(rename = "absolute")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub absolute: Option<f64>,
+
+    /// Percentage of the last published value
+    #[serde(rename = "percent")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f64>,
+}
+
+impl Deadband {
+    fn is_exceeded(&self, last: f64, value: f64) -> bool {
+        let change = (value - last).abs();
+        self.absolute.map(|a| change > a).unwrap_or(true)
+            && self
+                .percent
+                .map(|p| change > last.abs() * p / 100.0)
+                .unwrap_or(true)
+    }
+}
+
+/// Report-by-exception settings of a profile
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReportPolicy {
+    /// Shortest time between two publishes of a device's profile
+    #[serde(rename = "min-interval-ms")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_interval_ms: Option<u64>,
+
+    /// Publish at least this often even when nothing changed
+    #[serde(rename = "integrity-interval-ms")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrity_interval_ms: Option<u64>,
+
+    /// Numeric tags without a deadband are reported on any change
+    #[serde(rename = "deadbands", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deadbands: Vec<Deadband>,
+}
+
+impl ReportPolicy {
+    fn deadband(&self, tag: &str) -> Option<&Deadband> {
+        self.deadbands.iter().find(|d| d.tag == tag)
+    }
+
+    /// Whether `tag` changed significantly since `last` was published
+    fn is_significant(&self, last: &Tag, tag: &Tag) -> bool {
+        match (&last.value, &tag.value) {
+            (TagValue::F64(last_value), TagValue::F64(value)) if last.invalid == tag.invalid => {
+                match self.deadband(&tag.name) {
+                    Some(deadband) => deadband.is_exceeded(*last_value, *value),
+                    None => last_value != value,
+                }
+            }
+            _ => last != tag,
+        }
+    }
+
+    /// Whether any of `tags` changed significantly since `last` was published.  Tags that
+    /// appear or disappear are significant.
+    fn has_changed(&self, last: &[Tag], tags: &[Tag]) -> bool {
+        last.len() != tags.len()
+            || tags
+                .iter()
+                .any(|tag| match last.iter().find(|l| l.name == tag.name) {
+                    Some(l) => self.is_significant(l, tag),
+                    None => true,
+                })
+    }
+}
+
+struct Published<Q> {
+    at: Instant,
+    tags: Vec<Tag>,
+    quality: Q,
+}
+
+/// Decides which updates of a profile are published, per device.  `Q` is the quality the
+/// tags are published with; a change of quality is always published.
+pub struct ReportFilter<Q> {
+    policy: ReportPolicy,
+    last: HashMap<String, Published<Q>>,
+}
+
+impl<Q: Clone + PartialEq> ReportFilter<Q> {
+    pub fn new(policy: ReportPolicy) -> ReportFilter<Q> {
+        ReportFilter {
+            policy,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Check whether an update of `device_id` should be published and, if so, remember it as
+    /// the last published update
+    pub fn should_publish(
+        &mut self,
+        device_id: &str,
+        tags: &[Tag],
+        quality: &Q,
+        now: Instant,
+    ) -> bool {
+        let publish = match self.last.get(device_id) {
+            None => true,
+            Some(last) => {
+                let elapsed = now.saturating_duration_since(last.at);
+                let min_interval = self.policy.min_interval_ms.map(Duration::from_millis);
+                let integrity = self.policy.integrity_interval_ms.map(Duration::from_millis);
+
+                if last.quality != *quality {
+                    true
+                } else if matches!(min_interval, Some(min) if elapsed < min) {
+                    false
+                } else {
+                    matches!(integrity, Some(integrity) if elapsed >= integrity)
+                        || self.policy.has_changed(&last.tags, tags)
+                }
+            }
+        };
+
+        if publish {
+            self.last.insert(
+                device_id.to_string(),
+                Published {
+                    at: now,
+                    tags: tags.to_vec(),
+                    quality: quality.clone(),
+                },
+            );
+        }
+        publish
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OES_PLUG_POWER, OES_PLUG_STATUS, OES_PLUG_VOLTAGE};
+
+    const PLUG: &str = "80c955645cd4";
+
+    fn tags(power: f64, voltage: f64) -> Vec<Tag> {
+        vec![
+            Tag::new(OES_PLUG_POWER, TagValue::F64(power)),
+            Tag::new(OES_PLUG_VOLTAGE, TagValue::F64(voltage)),
+        ]
+    }
+
+    fn policy() -> ReportPolicy {
+        serde_json::from_str(
+            r#"{
+                "min-interval-ms": 1000,
+                "integrity-interval-ms": 60000,
+                "deadbands": [
+                    {"tag": "OES.Plug.Power", "absolute": 0.5, "percent": 2.0},
+                    {"tag": "OES.Plug.Voltage", "absolute": 1.0}
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn deadbands() {
+        let power = policy().deadbands[0].clone();
+        assert!(!power.is_exceeded(100.0, 101.5));
+        assert!(power.is_exceeded(100.0, 102.5));
+        // Near zero the absolute deadband applies
+        assert!(!power.is_exceeded(0.1, 0.5));
+        assert!(power.is_exceeded(0.1, 0.7));
+
+        let percent_only = Deadband {
+            tag: "x".to_string(),
+            absolute: None,
+            percent: Some(10.0),
+        };
+        assert!(percent_only.is_exceeded(-50.0, -56.0));
+        assert!(!percent_only.is_exceeded(-50.0, -54.0));
+    }
+
+    #[test]
+    fn reports_by_exception() {
+        let mut filter = ReportFilter::new(policy());
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert!(filter.should_publish(PLUG, &tags(100.0, 120.0), &1, at(0)));
+        // Significant change, but too soon after the last publish
+        assert!(!filter.should_publish(PLUG, &tags(150.0, 120.0), &1, at(500)));
+        // Within the deadband
+        assert!(!filter.should_publish(PLUG, &tags(101.0, 120.5), &1, at(1500)));
+        assert!(filter.should_publish(PLUG, &tags(150.0, 120.0), &1, at(2000)));
+        assert!(filter.should_publish(PLUG, &tags(150.0, 122.0), &1, at(3500)));
+
+        // Integrity interval
+        assert!(!filter.should_publish(PLUG, &tags(150.0, 122.0), &1, at(63000)));
+        assert!(filter.should_publish(PLUG, &tags(150.0, 122.0), &1, at(63500)));
+
+        // Quality changes are published immediately
+        assert!(filter.should_publish(PLUG, &tags(150.0, 122.0), &2, at(63600)));
+
+        // Devices are tracked separately
+        assert!(filter.should_publish("other", &tags(150.0, 122.0), &1, at(63600)));
+    }
+
+    #[test]
+    fn discrete_tags_report_any_change() {
+        let mut filter = ReportFilter::new(ReportPolicy::default());
+        let now = Instant::now();
+        let status = |v| vec![Tag::new(OES_PLUG_STATUS, TagValue::Enum(v))];
+
+        assert!(filter.should_publish(PLUG, &status(0), &(), now));
+        assert!(!filter.should_publish(PLUG, &status(0), &(), now));
+        assert!(filter.should_publish(PLUG, &status(1), &(), now));
+
+        let invalid = vec![status(1)[0].clone().with_invalid_quality()];
+        assert!(filter.should_publish(PLUG, &invalid, &(), now));
+
+        // Tags that appear are significant
+        let mut more = invalid.clone();
+        more.push(Tag::new(OES_PLUG_POWER, TagValue::F64(1.0)));
+        assert!(filter.should_publish(PLUG, &more, &(), now));
+    }
+}
@@ -0,0 +1,643 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    f64::consts::PI,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UdpSocket,
+    time::{interval, sleep},
+};
+
+use crate::{
+    CodecType, Data, OESPlug, OpRequest, OpResponse, Outlet, PlugStatus, ReplayGuard, Request, FC,
+};
+
+/// Errors injected into the datagrams a simulated plug sends
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Faults {
+    /// Probability that a heartbeat or reply is not sent
+    #[serde(rename = "loss", default)]
+    pub loss: f64,
+
+    /// Probability that a heartbeat or reply is cut short, so it no longer decodes
+    #[serde(rename = "malformed", default)]
+    pub malformed: f64,
+
+    /// Probability that a reply is delayed by `delay-ms`
+    #[serde(rename = "delayed", default)]
+    pub delayed: f64,
+
+    #[serde(rename = "delay-ms", default)]
+    pub delay_ms: u64,
+}
+
+impl Faults {
+    /// Apply loss and corruption to an encoded datagram; `None` when it is lost
+    pub fn corrupt<R: Rng>(&self, rng: &mut R, mut datagram: Vec<u8>) -> Option<Vec<u8>> {
+        if rng.gen_bool(self.loss.clamp(0.0, 1.0)) {
+            return None;
+        }
+        if rng.gen_bool(self.malformed.clamp(0.0, 1.0)) {
+            datagram.truncate(datagram.len() / 2);
+        }
+        Some(datagram)
+    }
+
+    /// How long to hold back a reply
+    pub fn reply_delay<R: Rng>(&self, rng: &mut R) -> Duration {
+        match rng.gen_bool(self.delayed.clamp(0.0, 1.0)) {
+            true => Duration::from_millis(self.delay_ms),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+fn default_heartbeat_ms() -> u64 {
+    1000
+}
+
+fn default_load() -> f64 {
+    60.0
+}
+
+fn default_voltage() -> f64 {
+    120.0
+}
+
+fn default_power_factor() -> f64 {
+    0.95
+}
+
+fn default_status() -> PlugStatus {
+    PlugStatus::On
+}
+
+/// One simulated plug
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SimulatedPlug {
+    #[serde(rename = "mac-address")]
+    pub mac_address: String,
+
+    #[serde(rename = "name", default)]
+    pub name: String,
+
+    /// Address the plug receives requests on and sends heartbeats from
+    #[serde(rename = "listen")]
+    pub listen: SocketAddr,
+
+    #[serde(rename = "heartbeat-ms", default = "default_heartbeat_ms")]
+    pub heartbeat_ms: u64,
+
+    /// Average load in W of the plug, or of each outlet, while switched on
+    #[serde(rename = "load-w", default = "default_load")]
+    pub load_w: f64,
+
+    /// Nominal line voltage
+    #[serde(rename = "voltage", default = "default_voltage")]
+    pub voltage: f64,
+
+    /// Nominal line frequency; the plug reports extended telemetry when set
+    #[serde(rename = "frequency")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<f64>,
+
+    #[serde(rename = "power-factor", default = "default_power_factor")]
+    pub power_factor: f64,
+
+    /// Relay state at start and after a reset
+    #[serde(rename = "status", default = "default_status")]
+    pub status: PlugStatus,
+
+    /// Number of outlets of a power strip, numbered from 1
+    #[serde(rename = "outlets", default)]
+    pub outlets: u32,
+
+    #[serde(rename = "codec", default)]
+    pub codec: CodecType,
+
+    #[serde(rename = "hmac-key")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hmac_key: Option<String>,
+
+    /// Faults of this plug, overriding the simulator setting
+    #[serde(rename = "faults")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub faults: Option<Faults>,
+}
+
+/// Plugs emulated by the simulator
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SimulatorConfiguration {
+    /// Address heartbeats are sent to, usually the adapter address and port
+    #[serde(rename = "adapter")]
+    pub adapter: SocketAddr,
+
+    /// Seed of the random number generators, for repeatable runs
+    #[serde(rename = "seed")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+
+    #[serde(rename = "faults", default)]
+    pub faults: Faults,
+
+    #[serde(rename = "plugs")]
+    pub plugs: Vec<SimulatedPlug>,
+}
+
+/// A request as received by a plug: the relay request sent by the adapter or an opcode request
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PlugRequest {
+    Op(OpRequest),
+    Relay(Request),
+}
+
+/// Answer of a plug: relay requests are answered with the plug state, opcode requests with the
+/// matching response
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum PlugReply {
+    Data(Data),
+    Op(OpResponse),
+}
+
+/// State and measurements of a simulated plug
+pub struct PlugModel {
+    config: SimulatedPlug,
+    status: PlugStatus,
+    outlets: Vec<PlugStatus>,
+    heartbeat: Duration,
+    rng: StdRng,
+}
+
+impl PlugModel {
+    pub fn new(config: SimulatedPlug, rng: StdRng) -> PlugModel {
+        let outlets = vec![config.status.clone(); config.outlets as usize];
+        let heartbeat = Duration::from_millis(config.heartbeat_ms.max(1));
+        PlugModel {
+            status: config.status.clone(),
+            outlets,
+            heartbeat,
+            config,
+            rng,
+        }
+    }
+
+    /// Current heartbeat interval
+    pub fn heartbeat(&self) -> Duration {
+        self.heartbeat
+    }
+
+    /// Current state of the plug relay, or of an outlet's relay
+    pub fn relay_status(&self, outlet: Option<u32>) -> PlugStatus {
+        match outlet {
+            Some(index) => self
+                .outlets
+                .get((index as usize).wrapping_sub(1))
+                .cloned()
+                .unwrap_or(PlugStatus::Unknown),
+            None => self.status.clone(),
+        }
+    }
+
+    /// Switch the plug, or one outlet; switching a power strip as a whole switches every outlet.
+    /// Returns false for an outlet the plug does not have.
+    pub fn switch(&mut self, on: bool, outlet: Option<u32>) -> bool {
+        let status = match on {
+            true => PlugStatus::On,
+            false => PlugStatus::Off,
+        };
+        match outlet {
+            Some(index) => match self.outlets.get_mut((index as usize).wrapping_sub(1)) {
+                Some(s) => *s = status.clone(),
+                None => return false,
+            },
+            None => self.outlets.iter_mut().for_each(|s| *s = status.clone()),
+        }
+        if !self.outlets.is_empty() {
+            self.status = match self.outlets.contains(&PlugStatus::On) {
+                true => PlugStatus::On,
+                false => PlugStatus::Off,
+            };
+        } else {
+            self.status = status;
+        }
+        true
+    }
+
+    /// Random variation of `amplitude` around zero
+    fn noise(&mut self, amplitude: f64) -> f64 {
+        self.rng.gen_range(-amplitude..=amplitude)
+    }
+
+    /// Power drawn by a load at `elapsed` seconds: a slow swing around the nominal load with
+    /// some ripple, each load at its own phase
+    fn load(&mut self, phase: f64, elapsed: f64) -> f64 {
+        let swing = 0.2 * (2.0 * PI * elapsed / 300.0 + phase).sin();
+        let ripple = self.noise(0.03);
+        (self.config.load_w * (1.0 + swing + ripple)).max(0.0)
+    }
+
+    /// Measurements at `elapsed` since the plug started
+    pub fn data(&mut self, elapsed: Duration) -> Data {
+        let t = elapsed.as_secs_f64();
+        let voltage = self.config.voltage * (1.0 + 0.01 * (2.0 * PI * t / 600.0).sin())
+            + self.noise(0.002 * self.config.voltage);
+        let pf = self.config.power_factor.clamp(0.01, 1.0);
+        let current = |power: f64| power / (voltage * pf);
+
+        let mut outlets = vec![];
+        for (i, status) in self.outlets.clone().into_iter().enumerate() {
+            let power = match status {
+                PlugStatus::On => self.load(i as f64, t),
+                _ => 0.0,
+            };
+            outlets.push(Outlet {
+                index: i as u32 + 1,
+                status,
+                power,
+                current: current(power),
+            });
+        }
+        let power = match (outlets.is_empty(), &self.status) {
+            (false, _) => outlets.iter().map(|o| o.power).sum(),
+            (true, PlugStatus::On) => self.load(0.0, t),
+            (true, _) => 0.0,
+        };
+
+        let mut data = Data {
+            ip_address: self.config.listen.ip().to_string(),
+            mac_address: self.config.mac_address.clone(),
+            name: self.config.name.clone(),
+            status: self.status.clone(),
+            power,
+            voltage,
+            current: current(power),
+            outlets,
+            ..Default::default()
+        };
+        if let Some(frequency) = self.config.frequency {
+            data.frequency =
+                Some(frequency + 0.02 * (2.0 * PI * t / 120.0).sin() + self.noise(0.005));
+            data.power_factor = Some(pf);
+            data.reactive_power = Some(power * pf.acos().tan());
+            data.temperature = Some(30.0 + 0.02 * power + self.noise(0.2));
+        }
+        data
+    }
+
+    /// Act on a request and build the reply
+    pub fn respond(&mut self, request: PlugRequest, elapsed: Duration) -> PlugReply {
+        match request {
+            PlugRequest::Relay(request) => {
+                if !self.switch(request.fc == FC::On, request.outlet) {
+                    log::warn!(
+                        "{} has no outlet {:?}",
+                        self.config.mac_address,
+                        request.outlet
+                    );
+                }
+                PlugReply::Data(self.data(elapsed))
+            }
+            PlugRequest::Op(request) => PlugReply::Op(match request {
+                OpRequest::SR => OpResponse::SR(self.data(elapsed)),
+                OpRequest::TC { time } => OpResponse::TC { time },
+                OpRequest::RM => {
+                    self.status = self.config.status.clone();
+                    self.outlets
+                        .iter_mut()
+                        .for_each(|s| *s = self.status.clone());
+                    OpResponse::RM
+                }
+                OpRequest::TT { interval_ms } => {
+                    self.heartbeat = Duration::from_millis(interval_ms.max(1) as u64);
+                    OpResponse::TT { interval_ms }
+                }
+            }),
+        }
+    }
+}
+
+/// A simulated plug on its own UDP socket
+pub struct PlugSimulator {
+    sock: Arc<UdpSocket>,
+    adapter: SocketAddr,
+    model: PlugModel,
+    device: OESPlug,
+    faults: Faults,
+    replay_guard: Mutex<ReplayGuard>,
+}
+
+impl PlugSimulator {
+    /// Bind the plug socket; faults of the plug take precedence over `faults`
+    pub async fn bind(
+        config: SimulatedPlug,
+        adapter: SocketAddr,
+        faults: &Faults,
+        rng: StdRng,
+    ) -> io::Result<PlugSimulator> {
+        let sock = UdpSocket::bind(config.listen).await?;
+        let device = OESPlug {
+            mac_address: config.mac_address.clone(),
+            codec: config.codec,
+            hmac_key: config.hmac_key.clone(),
+            ..Default::default()
+        };
+        Ok(PlugSimulator {
+            sock: Arc::new(sock),
+            adapter,
+            faults: config.faults.clone().unwrap_or_else(|| faults.clone()),
+            model: PlugModel::new(config, rng),
+            device,
+            replay_guard: Mutex::new(ReplayGuard::default()),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    fn encode<T: Serialize>(&mut self, value: &T) -> Option<Vec<u8>> {
+        match self.device.encode_datagram(value) {
+            Ok(datagram) => self.faults.corrupt(&mut self.model.rng, datagram),
+            Err(e) => {
+                log::error!("Unable to encode datagram: {}", e);
+                None
+            }
+        }
+    }
+
+    fn decode(&self, buf: &[u8]) -> Option<PlugRequest> {
+        match self.device.decode_datagram(buf, &self.replay_guard) {
+            Ok(request) => Some(request),
+            Err(e) => {
+                log::warn!("{} ignored request: {}", self.device.mac_address, e);
+                None
+            }
+        }
+    }
+
+    /// Send heartbeats and answer requests until the socket fails
+    pub async fn run(mut self) -> io::Result<()> {
+        let started = Instant::now();
+        let mut heartbeat = self.model.heartbeat();
+        let mut ticker = interval(heartbeat);
+        let mut buf = [0u8; 2048];
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    let data = self.model.data(started.elapsed());
+                    if let Some(datagram) = self.encode(&data) {
+                        if let Err(e) = self.sock.send_to(&datagram, self.adapter).await {
+                            log::error!("Failed to send heartbeat to {}: {}", self.adapter, e);
+                        }
+                    }
+                }
+                received = self.sock.recv_from(&mut buf) => {
+                    let (len, source) = received?;
+                    let request = match self.decode(&buf[0..len]) {
+                        Some(request) => request,
+                        None => continue,
+                    };
+                    log::debug!("{} received {:?} from {}", self.device.mac_address, request, source);
+
+                    let reply = self.model.respond(request, started.elapsed());
+                    let delay = self.faults.reply_delay(&mut self.model.rng);
+                    if let Some(datagram) = self.encode(&reply) {
+                        let sock = self.sock.clone();
+                        tokio::spawn(async move {
+                            sleep(delay).await;
+                            if let Err(e) = sock.send_to(&datagram, source).await {
+                                log::error!("Failed to send reply to {}: {}", source, e);
+                            }
+                        });
+                    }
+
+                    if self.model.heartbeat() != heartbeat {
+                        heartbeat = self.model.heartbeat();
+                        ticker = interval(heartbeat);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Run every configured plug until one of them fails.  Fails immediately when no plugs are configured.
+pub async fn simulate(config: SimulatorConfiguration) -> io::Result<()> {
+    if config.plugs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no plugs configured",
+        ));
+    }
+
+    let mut plugs = vec![];
+    for (i, plug) in config.plugs.into_iter().enumerate() {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(i as u64)),
+            None => StdRng::from_entropy(),
+        };
+        let simulator = PlugSimulator::bind(plug, config.adapter, &config.faults, rng).await?;
+        log::info!(
+            "Simulating {} on {}",
+            simulator.device.mac_address,
+            simulator.local_addr()?
+        );
+        plugs.push(tokio::spawn(simulator.run()));
+    }
+
+    let (result, _, _) = futures::future::select_all(plugs).await;
+    result.map_err(io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plug(outlets: u32) -> SimulatedPlug {
+        serde_yaml::from_str(&format!(
+            r#"
+mac-address: 80c955645cd4
+listen: 127.0.0.1:0
+frequency: 60
+outlets: {}
+"#,
+            outlets
+        ))
+        .unwrap()
+    }
+
+    fn model(outlets: u32) -> PlugModel {
+        PlugModel::new(plug(outlets), StdRng::seed_from_u64(7))
+    }
+
+    fn at(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn defaults() {
+        let plug = plug(0);
+        assert_eq!(plug.heartbeat_ms, 1000);
+        assert_eq!(plug.load_w, 60.0);
+        assert_eq!(plug.status, PlugStatus::On);
+        assert_eq!(plug.codec, CodecType::Json);
+        assert_eq!(plug.faults, None);
+    }
+
+    #[test]
+    fn realistic_measurements() {
+        let mut model = model(0);
+        for secs in 0..600 {
+            let data = model.data(at(secs));
+            assert!((115.0..125.0).contains(&data.voltage), "{}", data.voltage);
+            assert!((40.0..80.0).contains(&data.power), "{}", data.power);
+            let apparent = data.voltage * data.current;
+            assert!((data.power / apparent - 0.95).abs() < 1e-9);
+            assert!((59.9..60.1).contains(&data.frequency.unwrap()));
+        }
+
+        // Switched off, the plug draws nothing
+        model.switch(false, None);
+        let data = model.data(at(10));
+        assert_eq!(data.status, PlugStatus::Off);
+        assert_eq!(data.power, 0.0);
+        assert_eq!(data.current, 0.0);
+        assert!(data.voltage > 115.0);
+    }
+
+    #[test]
+    fn relay_requests() {
+        let mut model = model(0);
+        let reply = model.respond(PlugRequest::Relay(Request::relay(false)), at(1));
+        match reply {
+            PlugReply::Data(data) => assert!(Request::relay(false).is_acknowledged_by(&data)),
+            _ => panic!("expected data"),
+        }
+
+        let reply = model.respond(PlugRequest::Op(OpRequest::SR), at(2));
+        match reply {
+            PlugReply::Op(OpResponse::SR(data)) => assert_eq!(data.status, PlugStatus::Off),
+            _ => panic!("expected status report"),
+        }
+
+        let reply = model.respond(PlugRequest::Op(OpRequest::TT { interval_ms: 250 }), at(3));
+        assert_eq!(reply, PlugReply::Op(OpResponse::TT { interval_ms: 250 }));
+        assert_eq!(model.heartbeat(), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn power_strip_outlets() {
+        let mut model = model(3);
+        let request = Request::relay(false).with_outlet(Some(2));
+        let data = match model.respond(PlugRequest::Relay(request.clone()), at(1)) {
+            PlugReply::Data(data) => data,
+            _ => panic!("expected data"),
+        };
+        assert!(request.is_acknowledged_by(&data));
+        assert_eq!(data.status, PlugStatus::On);
+        assert_eq!(data.outlets.len(), 3);
+        assert_eq!(data.outlet(2).unwrap().power, 0.0);
+        let total: f64 = data.outlets.iter().map(|o| o.power).sum();
+        assert_eq!(data.power, total);
+
+        // The strip is off once every outlet is
+        model.switch(false, None);
+        assert_eq!(model.relay_status(None), PlugStatus::Off);
+        assert!(!model.switch(true, Some(4)));
+        let reply = model.respond(PlugRequest::Op(OpRequest::RM), at(2));
+        assert_eq!(reply, PlugReply::Op(OpResponse::RM));
+        assert_eq!(model.relay_status(Some(3)), PlugStatus::On);
+    }
+
+    #[test]
+    fn requests_are_decoded_by_shape() {
+        let relay: PlugRequest = serde_json::from_str(r#"{"fc":"On","outlet":2}"#).unwrap();
+        assert!(matches!(
+            relay,
+            PlugRequest::Relay(Request {
+                fc: FC::On,
+                outlet: Some(2)
+            })
+        ));
+        let op: PlugRequest = serde_json::from_str(r#"{"op":"SR"}"#).unwrap();
+        assert!(matches!(op, PlugRequest::Op(OpRequest::SR)));
+    }
+
+    #[test]
+    fn faults() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let datagram = b"{\"fc\":\"On\"}".to_vec();
+
+        assert_eq!(
+            Faults::default().corrupt(&mut rng, datagram.clone()),
+            Some(datagram.clone())
+        );
+        let lossy = Faults {
+            loss: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(lossy.corrupt(&mut rng, datagram.clone()), None);
+        let malformed = Faults {
+            malformed: 1.0,
+            ..Default::default()
+        };
+        let corrupted = malformed.corrupt(&mut rng, datagram.clone()).unwrap();
+        assert!(serde_json::from_slice::<Request>(&corrupted).is_err());
+
+        let delayed = Faults {
+            delayed: 1.0,
+            delay_ms: 1500,
+            ..Default::default()
+        };
+        assert_eq!(delayed.reply_delay(&mut rng), Duration::from_millis(1500));
+        assert_eq!(Faults::default().reply_delay(&mut rng), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn answers_requests_over_udp() {
+        let adapter = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let simulator = PlugSimulator::bind(
+            plug(0),
+            adapter.local_addr().unwrap(),
+            &Faults::default(),
+            StdRng::seed_from_u64(3),
+        )
+        .await
+        .unwrap();
+        let address = simulator.local_addr().unwrap();
+        tokio::spawn(simulator.run());
+
+        // Heartbeats arrive at the adapter
+        let mut buf = [0u8; 2048];
+        let (len, source) = adapter.recv_from(&mut buf).await.unwrap();
+        assert_eq!(source, address);
+        let data: Data = serde_json::from_slice(&buf[0..len]).unwrap();
+        assert_eq!(data.mac_address, "80c955645cd4");
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(br#"{"fc":"Off"}"#, address).await.unwrap();
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        let reply: Data = serde_json::from_slice(&buf[0..len]).unwrap();
+        assert_eq!(reply.status, PlugStatus::Off);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_simulate_no_plugs() {
+        let config: SimulatorConfiguration =
+            serde_yaml::from_str("adapter: 127.0.0.1:8555\nplugs: []\n").unwrap();
+        let error = simulate(config).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
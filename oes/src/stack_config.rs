@@ -2,10 +2,18 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    parse_socket_address, resolve, AggregationConfiguration, BinaryField, CodecType, HostCache,
+    ReportPolicy, TagMapping,
+};
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
 pub struct StackConfiguration {
     #[serde(rename = "adapter-ip-address")]
@@ -20,9 +28,105 @@ pub struct StackConfiguration {
     #[serde(rename = "controllable-plugs")]
     pub controllable_plugs: Vec<OESPlug>,
 
+    /// Default status polling interval for every plug; plugs are not polled when absent or zero
+    #[serde(rename = "poll-interval-ms")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_interval_ms: Option<u64>,
+
+    /// Plugs that have not been heard from within this time are published with old/invalid quality
+    #[serde(rename = "liveness-timeout-ms")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub liveness_timeout_ms: Option<u64>,
+
+    /// What to do with heartbeats whose source does not match the configured plug address
+    #[serde(rename = "source-validation", default)]
+    pub source_validation: SourceValidation,
+
+    /// Also require the source port to match the configured plug port
+    #[serde(rename = "source-validation-check-port", default)]
+    pub source_validation_check_port: bool,
+
+    /// Tags extracted from incoming JSON datagrams in addition to the OES plug fields
+    #[serde(rename = "tags", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<TagMapping>,
+
+    /// Path of the device identifier in datagrams that are not OES plug heartbeats
+    #[serde(rename = "mac-address-path")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac_address_path: Option<String>,
+
+    /// File in which energy totals accumulated from plug power are kept across restarts
+    #[serde(rename = "energy-file")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub energy_file: Option<String>,
+
+    /// Directory in which the counters of authenticated plugs are kept across restarts
+    #[serde(rename = "replay-dir")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay_dir: Option<String>,
+
+    /// Multicast groups (IPv4 or IPv6) to join for receiving heartbeats
+    #[serde(rename = "multicast-groups", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub multicast_groups: Vec<IpAddr>,
+
+    /// Interface on which to join the multicast groups; the system default is used when absent
+    #[serde(rename = "multicast-interface")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multicast_interface: Option<MulticastInterface>,
+
+    /// Set SO_REUSEADDR on the adapter socket
+    #[serde(rename = "reuse-address", default)]
+    pub reuse_address: bool,
+
+    /// Set SO_REUSEPORT on the adapter socket so several adapters can share `adapter-port` (Unix only)
+    #[serde(rename = "reuse-port", default)]
+    pub reuse_port: bool,
+
+    /// Set SO_BROADCAST so status requests and commands can be sent to broadcast addresses
+    #[serde(rename = "broadcast", default)]
+    pub broadcast: bool,
+
+    /// Publish reading profiles at a fixed rate with statistics over a window instead of on
+    /// every heartbeat
+    #[serde(rename = "aggregation")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggregation: Option<AggregationConfiguration>,
+
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     pub profiles: Vec<Profile>,
+
+    /// Addresses of plug hostnames, resolved by `Connector::connect`
+    #[serde(skip_serializing)]
+    #[serde(skip_deserializing)]
+    pub hosts: HostCache,
+}
+
+/// Policy for heartbeats received from an address other than the configured plug's
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum SourceValidation {
+    /// Accept datagrams from any source
+    #[default]
+    #[serde(rename = "disabled")]
+    Disabled,
+    /// Accept the datagram but log and count the mismatch
+    #[serde(rename = "flag")]
+    Flag,
+    /// Log, count and discard the datagram
+    #[serde(rename = "drop")]
+    Drop,
+}
+
+/// Interface used to join multicast groups
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MulticastInterface {
+    /// Local IPv4 address of the interface, used for IPv4 groups
+    Address(Ipv4Addr),
+    /// Interface index, used for IPv6 groups
+    Index(u32),
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
@@ -38,12 +142,120 @@ pub struct OESPlug {
 
     #[serde(rename = "mrid")]
     pub mrid: String,
+
+    /// Encoding of heartbeats and commands exchanged with this plug
+    #[serde(rename = "codec", default)]
+    pub codec: CodecType,
+
+    /// Layout of fixed binary frames sent by this device; the codec is not used when set
+    #[serde(rename = "binary-layout", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub binary_layout: Vec<BinaryField>,
+
+    /// `ip-address` is a subnet broadcast address shared with other plugs, so replies are matched
+    /// by MAC address rather than source address
+    #[serde(rename = "group-address", default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub group_address: bool,
+
+    #[serde(rename = "command-timeout-ms")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_timeout_ms: Option<u64>,
+
+    #[serde(rename = "command-retries")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_retries: Option<u32>,
+
+    #[serde(rename = "command-backoff-ms")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_backoff_ms: Option<u64>,
+
+    /// Shared secret for authenticating datagrams with HMAC-SHA256; datagrams are not authenticated when absent
+    #[serde(rename = "hmac-key")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hmac_key: Option<String>,
+
+    /// Status polling interval for this plug, overrides the stack setting
+    #[serde(rename = "poll-interval-ms")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_interval_ms: Option<u64>,
+
+    /// Outlets of a multi-outlet plug, each published and controlled under its own mRID
+    #[serde(rename = "outlets", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub outlets: Vec<OESOutlet>,
+}
+
+/// An outlet of a multi-outlet plug
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+pub struct OESOutlet {
+    /// Outlet index reported by the plug
+    #[serde(rename = "index")]
+    pub index: u32,
+
+    #[serde(rename = "mrid")]
+    pub mrid: String,
+}
+
+impl OESPlug {
+    /// Socket address of the plug when `ip-address` is an IP literal
+    pub fn socket_address(&self) -> std::io::Result<SocketAddr> {
+        parse_socket_address(&self.ip_address, self.port)
+    }
+
+    /// Resolve the plug address; `ip-address` may be an IPv4 or IPv6 literal or a hostname
+    pub async fn resolve(&self) -> std::io::Result<SocketAddr> {
+        resolve(&self.ip_address, self.port).await
+    }
+
+    /// Check whether `ip` is one of this plug's addresses, with hostnames looked up in `hosts`
+    pub fn has_ip(&self, ip: &IpAddr, hosts: &HostCache) -> bool {
+        hosts.ips(&self.ip_address).contains(&ip.to_canonical())
+    }
+
+    /// Retry policy used when sending commands to this plug
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            timeout: self
+                .command_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default.timeout),
+            retries: self.command_retries.unwrap_or(default.retries),
+            backoff: self
+                .command_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default.backoff),
+        }
+    }
+}
+
+/// How long to wait for a plug to acknowledge a command and how often to retry.
+/// The delay between attempts starts at `backoff` and doubles after every retry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            timeout: Duration::from_millis(1000),
+            retries: 2,
+            backoff: Duration::from_millis(200),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
 pub struct Profile {
     pub name: String,
     pub content: String,
+    /// Report-by-exception settings; every update is published when absent
+    #[serde(default)]
+    pub report: Option<ReportPolicy>,
 }
 
 impl StackConfiguration {
@@ -57,19 +269,174 @@ impl StackConfiguration {
         None
     }
 
-    /// Look up socker address for a device from its mRID
-    pub fn lookup_socker_address_for_controllable(&self, mrid: &str) -> Option<SocketAddr> {
-        for p in &self.controllable_plugs {
-            if p.mrid == mrid {
-                match format!("{}:{}", p.ip_address, p.port).parse::<SocketAddr>() {
-                    Ok(addr) => return Some(addr),
-                    Err(e) => {
-                        log::error!("Failed to parse address: {}", e);
-                        return None;
-                    }
-                }
-            }
+    /// Look up mRID for an outlet of a multi-outlet plug
+    pub fn lookup_mrid_for_outlet(&self, mac_address: &str, index: u32) -> Option<String> {
+        self.lookup_uncontrollable(mac_address)?
+            .outlets
+            .iter()
+            .find(|o| o.index == index)
+            .map(|o| o.mrid.clone())
+    }
+
+    /// Look up mRID for a whole plug (`outlet` is `None`) or one of its outlets
+    pub fn lookup_mrid(&self, mac_address: &str, outlet: Option<u32>) -> Option<String> {
+        match outlet {
+            Some(index) => self.lookup_mrid_for_outlet(mac_address, index),
+            None => self.lookup_mrid_for_uncontrollable(mac_address),
         }
-        None
+    }
+
+    /// Look up a controllable plug from its mRID
+    pub fn lookup_controllable(&self, mrid: &str) -> Option<&OESPlug> {
+        self.controllable_plugs.iter().find(|p| p.mrid == mrid)
+    }
+
+    /// Look up a controllable plug from its mRID or the mRID of one of its outlets.  The outlet
+    /// index is returned when `mrid` belongs to an outlet.
+    pub fn lookup_controllable_target(&self, mrid: &str) -> Option<(&OESPlug, Option<u32>)> {
+        if let Some(plug) = self.lookup_controllable(mrid) {
+            return Some((plug, None));
+        }
+        self.controllable_plugs.iter().find_map(|p| {
+            p.outlets
+                .iter()
+                .find(|o| o.mrid == mrid)
+                .map(|o| (p, Some(o.index)))
+        })
+    }
+
+    /// Status polling interval for a plug, if the plug is to be polled
+    pub fn poll_interval(&self, plug: &OESPlug) -> Option<Duration> {
+        match plug.poll_interval_ms.or(self.poll_interval_ms) {
+            Some(ms) if ms > 0 => Some(Duration::from_millis(ms)),
+            _ => None,
+        }
+    }
+
+    /// Check settings that are accepted by the parser but would have no effect
+    pub fn validate(&self) -> Result<(), String> {
+        // Only monitored plugs are polled; their status reports are what gets published
+        match self
+            .controllable_plugs
+            .iter()
+            .find(|p| p.poll_interval_ms.is_some())
+        {
+            Some(plug) => Err(format!(
+                "poll-interval-ms is set on controllable plug {}; set it on the plug under uncontrollable-plugs",
+                plug.mac_address
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Time without heartbeats after which a plug's data is considered stale
+    pub fn liveness_timeout(&self) -> Option<Duration> {
+        match self.liveness_timeout_ms {
+            Some(ms) if ms > 0 => Some(Duration::from_millis(ms)),
+            _ => None,
+        }
+    }
+
+    /// Look up a monitored plug from its MAC address
+    pub fn lookup_uncontrollable(&self, mac_address: &str) -> Option<&OESPlug> {
+        self.uncontrollable_plugs
+            .iter()
+            .find(|p| p.mac_address == mac_address)
+    }
+
+    /// Plug addresses that are hostnames rather than IP literals
+    pub fn hostnames(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self
+            .uncontrollable_plugs
+            .iter()
+            .chain(self.controllable_plugs.iter())
+            .filter(|p| p.socket_address().is_err())
+            .map(|p| p.ip_address.clone())
+            .collect();
+        hosts.sort();
+        hosts.dedup();
+        hosts
+    }
+
+    /// Look up the configured plug at a source address
+    pub fn lookup_plug_for_address(&self, address: &SocketAddr) -> Option<&OESPlug> {
+        self.uncontrollable_plugs
+            .iter()
+            .chain(self.controllable_plugs.iter())
+            .find(|p| p.has_ip(&address.ip(), &self.hosts))
+    }
+
+    /// Look up the codec of the plug at a source address.  Unknown senders are assumed to use JSON.
+    pub fn lookup_codec_for_address(&self, address: &SocketAddr) -> CodecType {
+        self.lookup_plug_for_address(address)
+            .map(|p| p.codec)
+            .unwrap_or_default()
+    }
+
+    /// Check that a datagram claiming to come from `mac_address` was sent from that plug's
+    /// configured address.  Returns `false` for unknown MAC addresses.
+    pub fn is_expected_source(&self, mac_address: &str, source: &SocketAddr) -> bool {
+        self.uncontrollable_plugs
+            .iter()
+            .filter(|p| p.mac_address == mac_address)
+            .any(|p| {
+                p.has_ip(&source.ip(), &self.hosts)
+                    && (!self.source_validation_check_port || p.port == source.port() as u32)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plug(mac_address: &str, poll_interval_ms: Option<u64>) -> OESPlug {
+        OESPlug {
+            ip_address: "192.168.1.10".to_string(),
+            mac_address: mac_address.to_string(),
+            poll_interval_ms,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn poll_interval_only_on_monitored_plugs() {
+        let mut config = StackConfiguration {
+            poll_interval_ms: Some(10000),
+            uncontrollable_plugs: vec![plug("80c955645cd4", Some(2000))],
+            controllable_plugs: vec![plug("80c955645cd4", None)],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.poll_interval(&config.uncontrollable_plugs[0]),
+            Some(Duration::from_millis(2000))
+        );
+
+        config.controllable_plugs[0].poll_interval_ms = Some(2000);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn group_address_is_opt_in() {
+        let plugs: Vec<OESPlug> = serde_yaml::from_str(
+            "
+- ip-address: 10.0.1.255
+  port: 8556
+  mac-address: 80c955645cd4
+  mrid: a
+- ip-address: 192.168.1.255
+  port: 8556
+  mac-address: 80c955645cd5
+  mrid: b
+  group-address: true
+",
+        )
+        .unwrap();
+        assert!(!plugs[0].group_address);
+        assert!(plugs[1].group_address);
+        assert!(!serde_yaml::to_string(&plugs[0])
+            .unwrap()
+            .contains("group-address"));
     }
 }
@@ -0,0 +1,21 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters for datagrams received on the adapter socket
+#[derive(Debug, Default)]
+pub struct DatagramStats {
+    pub received: AtomicU64,
+    pub decode_errors: AtomicU64,
+    pub source_mismatches: AtomicU64,
+    pub auth_failures: AtomicU64,
+}
+
+impl DatagramStats {
+    /// Increment a counter and return its new value
+    pub fn increment(counter: &AtomicU64) -> u64 {
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
@@ -0,0 +1,661 @@
+// SPDX-FileCopyrightText: 2022 Open Energy Solutions Inc
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::BTreeMap, net::SocketAddr, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    Data, DatagramError, EnergyTotals, OESPlug, PlugStatus, ReplayGuard, StackConfiguration,
+    OES_PLUG_CURRENT, OES_PLUG_ENERGY, OES_PLUG_ENERGY_DEMAND, OES_PLUG_ENERGY_SUPPLY,
+    OES_PLUG_FREQUENCY, OES_PLUG_POWER, OES_PLUG_STATUS, OES_PLUG_VOLTAGE,
+};
+
+/// Type of a tag extracted from a datagram
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum TagType {
+    #[default]
+    #[serde(rename = "f64")]
+    F64,
+    #[serde(rename = "bool")]
+    Bool,
+    #[serde(rename = "string")]
+    String,
+    /// Device values translated to the enum values of the template `mapping`
+    #[serde(rename = "enum")]
+    Enum,
+}
+
+/// Value of a tag handed to the profile visitors
+#[derive(Clone, Debug, PartialEq)]
+pub enum TagValue {
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Enum(i32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub value: TagValue,
+    /// Publish with invalid quality whatever the quality of the rest of the reading
+    pub invalid: bool,
+}
+
+impl Tag {
+    pub fn new(name: &str, value: TagValue) -> Tag {
+        Tag {
+            name: name.to_string(),
+            value,
+            invalid: false,
+        }
+    }
+
+    /// Mark the value as invalid
+    pub fn with_invalid_quality(mut self) -> Tag {
+        self.invalid = true;
+        self
+    }
+
+    /// Name of the quality point that accompanies this tag, e.g. `OES.Plug.Power.Quality`
+    pub fn quality_name(&self) -> String {
+        format!("{}.Quality", self.name)
+    }
+
+    /// Name of the timestamp point that accompanies this tag, e.g. `OES.Plug.Power.Timestamp`
+    pub fn timestamp_name(&self) -> String {
+        format!("{}.Timestamp", self.name)
+    }
+}
+
+/// Tags decoded from one datagram, keyed by the MAC address of the device that sent it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Reading {
+    pub mac_address: String,
+    /// Outlet of a multi-outlet plug the tags belong to; `None` for the plug as a whole
+    pub outlet: Option<u32>,
+    pub tags: Vec<Tag>,
+    /// Device timestamp with time quality bits, if the device supplied one
+    pub timestamp: Option<String>,
+    /// Device quality bit string, if the device supplied one
+    pub quality: Option<String>,
+}
+
+impl Reading {
+    /// Key identifying the plug, or the outlet of a multi-outlet plug, e.g. `80c955645cd4/2`
+    pub fn device_id(&self) -> String {
+        match self.outlet {
+            Some(index) => format!("{}/{}", self.mac_address, index),
+            None => self.mac_address.clone(),
+        }
+    }
+
+    /// Value of a numeric tag
+    pub fn f64(&self, name: &str) -> Option<f64> {
+        self.tags.iter().rev().find_map(|t| match &t.value {
+            TagValue::F64(v) if t.name == name => Some(*v),
+            _ => None,
+        })
+    }
+}
+
+/// Maps a value in an incoming JSON datagram to a tag
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TagMapping {
+    #[serde(rename = "name")]
+    pub name: String,
+
+    /// JSON pointer (`/readings/0/power`) or dotted path (`readings[0].power`)
+    #[serde(rename = "path")]
+    pub path: String,
+
+    #[serde(rename = "type", default)]
+    pub tag_type: TagType,
+
+    /// Device values of an enum tag and the enum values they map to
+    #[serde(rename = "values", default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub values: BTreeMap<String, i32>,
+}
+
+impl TagMapping {
+    /// Extract the tag from a datagram.  Missing values and values that cannot be converted to
+    /// the tag type are skipped.
+    pub fn extract(&self, value: &Value) -> Option<Tag> {
+        let raw = lookup(value, &self.path)?;
+        match convert(raw, self.tag_type, &self.values) {
+            Some(v) => Some(Tag::new(&self.name, v)),
+            None => {
+                log::warn!(
+                    "Unable to convert {} at {} to {:?} for {}",
+                    raw,
+                    self.path,
+                    self.tag_type,
+                    self.name
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Convert a raw value to a tag value.  Enum values are translated through `values`, or taken
+/// as is when no translation is configured.
+pub(crate) fn convert(
+    raw: &Value,
+    tag_type: TagType,
+    values: &BTreeMap<String, i32>,
+) -> Option<TagValue> {
+    match tag_type {
+        TagType::F64 => to_f64(raw).map(TagValue::F64),
+        TagType::Bool => to_bool(raw).map(TagValue::Bool),
+        TagType::String => Some(TagValue::String(to_string(raw))),
+        TagType::Enum if values.is_empty() => raw
+            .as_i64()
+            .and_then(|v| i32::try_from(v).ok())
+            .map(TagValue::Enum),
+        TagType::Enum => values.get(&to_string(raw)).map(|v| TagValue::Enum(*v)),
+    }
+}
+
+/// Extract every mapped tag present in a datagram
+pub fn extract_tags(mappings: &[TagMapping], value: &Value) -> Vec<Tag> {
+    mappings.iter().filter_map(|m| m.extract(value)).collect()
+}
+
+/// Look up a value by JSON pointer or dotted path
+pub fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    value.pointer(&to_pointer(path))
+}
+
+/// Convert a dotted path such as `$.readings[0].power` to a JSON pointer; pointers are kept as is
+pub fn to_pointer(path: &str) -> String {
+    if path.is_empty() || path.starts_with('/') {
+        return path.to_string();
+    }
+
+    let path = path.strip_prefix("$.").unwrap_or(path);
+    let mut pointer = String::new();
+    for segment in path.split('.') {
+        let (key, indexes) = match segment.find('[') {
+            Some(i) => segment.split_at(i),
+            None => (segment, ""),
+        };
+        if !key.is_empty() {
+            pointer.push('/');
+            pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+        }
+        for index in indexes.split(['[', ']']).filter(|i| !i.is_empty()) {
+            pointer.push('/');
+            pointer.push_str(index);
+        }
+    }
+    pointer
+}
+
+fn to_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn to_bool(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(b) => Some(*b),
+        Value::Number(n) => n.as_f64().map(|n| n != 0.0),
+        Value::String(s) => match s.trim().to_lowercase().as_str() {
+            "true" | "on" | "1" => Some(true),
+            "false" | "off" | "0" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        _ => value.to_string(),
+    }
+}
+
+impl StackConfiguration {
+    /// Decode a datagram with the settings of the configured plug at `source`.  The reading of
+    /// the device comes first, followed by one reading per outlet of a multi-outlet plug.
+    pub fn decode_readings(
+        &self,
+        buf: &[u8],
+        source: &SocketAddr,
+        replay_guard: &Mutex<ReplayGuard>,
+    ) -> Result<Vec<Reading>, DatagramError> {
+        let default = OESPlug::default();
+        let plug = self.lookup_plug_for_address(source).unwrap_or(&default);
+        if plug.is_binary() {
+            return Ok(vec![plug.decode_frame(buf, replay_guard)?]);
+        }
+
+        let value = plug.decode_datagram::<Value>(buf, replay_guard)?;
+        let reading = self
+            .to_reading(&value, source)
+            .ok_or(DatagramError::Decode("No tags found in datagram"))?;
+
+        let mut readings = vec![];
+        if let Ok(data) = Data::deserialize(&value) {
+            readings = data.outlet_readings();
+        }
+        readings.insert(0, reading);
+        Ok(readings)
+    }
+
+    /// Turn a decoded datagram into a reading.  OES plug heartbeats are recognised by their
+    /// fields and the `tags` table is applied to every datagram, so other JSON devices can be
+    /// mapped too.  Devices that do not report an OES MAC address are identified by
+    /// `mac-address-path`, or else by the configured plug at the source address.
+    pub fn to_reading(&self, value: &Value, source: &SocketAddr) -> Option<Reading> {
+        let data = Data::deserialize(value).ok();
+
+        let mut tags = data.as_ref().map(|d| d.tags()).unwrap_or_default();
+        tags.extend(extract_tags(&self.tags, value));
+        if tags.is_empty() {
+            return None;
+        }
+
+        let (timestamp, quality) = match &data {
+            Some(data) => (data.timestamp.clone(), data.quality.clone()),
+            None => (None, None),
+        };
+
+        let mac_address = match data {
+            Some(data) => data.mac_address,
+            None => match self
+                .mac_address_path
+                .as_ref()
+                .and_then(|p| lookup(value, p))
+            {
+                Some(mac_address) => to_string(mac_address),
+                None => self.lookup_plug_for_address(source)?.mac_address.clone(),
+            },
+        };
+
+        Some(Reading {
+            mac_address,
+            outlet: None,
+            tags,
+            timestamp,
+            quality,
+        })
+    }
+}
+
+fn status_tag(status: &PlugStatus) -> Tag {
+    let tag = Tag::new(OES_PLUG_STATUS, TagValue::Enum(status.position()));
+    match status {
+        PlugStatus::Unknown => tag.with_invalid_quality(),
+        _ => tag,
+    }
+}
+
+impl Data {
+    /// Tags of an OES plug heartbeat
+    pub fn tags(&self) -> Vec<Tag> {
+        let mut tags = vec![
+            status_tag(&self.status),
+            Tag::new(OES_PLUG_POWER, TagValue::F64(self.power)),
+            Tag::new(OES_PLUG_VOLTAGE, TagValue::F64(self.voltage)),
+            Tag::new(OES_PLUG_CURRENT, TagValue::F64(self.current)),
+        ];
+        for (name, _quality, value) in self.extended_telemetry() {
+            tags.push(Tag::new(name, TagValue::F64(value)));
+        }
+        tags
+    }
+
+    /// One reading per outlet of a multi-outlet plug, with the voltage and frequency shared by
+    /// all outlets
+    pub fn outlet_readings(&self) -> Vec<Reading> {
+        self.outlets
+            .iter()
+            .map(|outlet| {
+                let mut tags = vec![
+                    status_tag(&outlet.status),
+                    Tag::new(OES_PLUG_POWER, TagValue::F64(outlet.power)),
+                    Tag::new(OES_PLUG_VOLTAGE, TagValue::F64(self.voltage)),
+                    Tag::new(OES_PLUG_CURRENT, TagValue::F64(outlet.current)),
+                ];
+                if let Some(frequency) = self.frequency {
+                    tags.push(Tag::new(OES_PLUG_FREQUENCY, TagValue::F64(frequency)));
+                }
+                Reading {
+                    mac_address: self.mac_address.clone(),
+                    outlet: Some(outlet.index),
+                    tags,
+                    timestamp: self.timestamp.clone(),
+                    quality: self.quality.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl EnergyTotals {
+    /// Energy tags, rounded to whole watt-hours
+    pub fn tags(&self) -> Vec<Tag> {
+        vec![
+            Tag::new(OES_PLUG_ENERGY, TagValue::F64(self.total_wh().round())),
+            Tag::new(
+                OES_PLUG_ENERGY_DEMAND,
+                TagValue::F64(self.demand_wh.round()),
+            ),
+            Tag::new(
+                OES_PLUG_ENERGY_SUPPLY,
+                TagValue::F64(self.supply_wh.round()),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mapping(name: &str, path: &str, tag_type: TagType) -> TagMapping {
+        TagMapping {
+            name: name.to_string(),
+            path: path.to_string(),
+            tag_type,
+            values: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn converts_paths_to_pointers() {
+        assert_eq!(to_pointer("/readings/0/power"), "/readings/0/power");
+        assert_eq!(to_pointer("readings[0].power"), "/readings/0/power");
+        assert_eq!(to_pointer("$.meter.phases[1][2]"), "/meter/phases/1/2");
+        assert_eq!(to_pointer("a/b.c~d"), "/a~1b/c~0d");
+        assert_eq!(to_pointer(""), "");
+    }
+
+    #[test]
+    fn extracts_typed_tags() {
+        let datagram = json!({
+            "id": "meter-7",
+            "readings": [{"power": 151.5, "volts": "119.8"}],
+            "relay": "ON",
+            "online": 1,
+            "state": 2,
+        });
+
+        let mut relay = mapping("Meter.Relay", "relay", TagType::Enum);
+        relay.values = BTreeMap::from([("ON".to_string(), 1), ("OFF".to_string(), 0)]);
+        let mappings = vec![
+            mapping("Meter.Power", "/readings/0/power", TagType::F64),
+            mapping("Meter.Voltage", "readings[0].volts", TagType::F64),
+            mapping("Meter.Name", "id", TagType::String),
+            mapping("Meter.Online", "online", TagType::Bool),
+            mapping("Meter.State", "state", TagType::Enum),
+            relay,
+        ];
+
+        assert_eq!(
+            extract_tags(&mappings, &datagram),
+            vec![
+                Tag::new("Meter.Power", TagValue::F64(151.5)),
+                Tag::new("Meter.Voltage", TagValue::F64(119.8)),
+                Tag::new("Meter.Name", TagValue::String("meter-7".to_string())),
+                Tag::new("Meter.Online", TagValue::Bool(true)),
+                Tag::new("Meter.State", TagValue::Enum(2)),
+                Tag::new("Meter.Relay", TagValue::Enum(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_missing_and_invalid_values() {
+        let datagram = json!({"power": "n/a", "relay": "BLINK", "state": 4294967298i64});
+        let mut relay = mapping("Meter.Relay", "relay", TagType::Enum);
+        relay.values = BTreeMap::from([("ON".to_string(), 1)]);
+        let mappings = vec![
+            mapping("Meter.Power", "power", TagType::F64),
+            mapping("Meter.Current", "current", TagType::F64),
+            mapping("Meter.State", "state", TagType::Enum),
+            relay,
+        ];
+        assert!(extract_tags(&mappings, &datagram).is_empty());
+    }
+
+    #[test]
+    fn parses_tag_table() {
+        let mappings: Vec<TagMapping> = serde_yaml::from_str(
+            r#"
+- name: Meter.Power
+  path: /readings/0/power
+- name: Meter.Relay
+  path: relay
+  type: enum
+  values:
+    "ON": 1
+    "OFF": 0
+"#,
+        )
+        .unwrap();
+        assert_eq!(mappings[0].tag_type, TagType::F64);
+        assert_eq!(mappings[1].tag_type, TagType::Enum);
+        assert_eq!(mappings[1].values.get("OFF"), Some(&0));
+    }
+
+    #[test]
+    fn identifies_devices() {
+        let mut config: StackConfiguration = serde_yaml::from_str(
+            r#"
+adapter-ip-address: 0.0.0.0
+adapter-port: 8555
+uncontrollable-plugs:
+  - mac-address: meter-7
+    mrid: 5f7436ec-7bf2-4f23-a5c4-fbf25d909ba2
+    ip-address: 192.168.86.40
+    port: 8556
+controllable-plugs: []
+tags:
+  - name: Meter.Power
+    path: readings[0].power
+"#,
+        )
+        .unwrap();
+        let meter: SocketAddr = "192.168.86.40:8556".parse().unwrap();
+        let other: SocketAddr = "192.168.86.41:8556".parse().unwrap();
+
+        // OES heartbeats carry their MAC address and also get the table applied
+        let heartbeat = serde_json::to_value(Data {
+            mac_address: "80c955645cd4".to_string(),
+            power: 5.0,
+            ..Default::default()
+        })
+        .unwrap();
+        let reading = config.to_reading(&heartbeat, &other).unwrap();
+        assert_eq!(reading.mac_address, "80c955645cd4");
+        assert_eq!(reading.f64(OES_PLUG_POWER), Some(5.0));
+
+        // Other devices are identified by source address
+        let datagram = json!({"serial": "A1", "readings": [{"power": 151.5}]});
+        let reading = config.to_reading(&datagram, &meter).unwrap();
+        assert_eq!(reading.mac_address, "meter-7");
+        assert_eq!(
+            reading.tags,
+            vec![Tag::new("Meter.Power", TagValue::F64(151.5))]
+        );
+        assert_eq!(config.to_reading(&datagram, &other), None);
+
+        // ... or by a path in the datagram
+        config.mac_address_path = Some("serial".to_string());
+        assert_eq!(
+            config.to_reading(&datagram, &other).unwrap().mac_address,
+            "A1"
+        );
+
+        assert_eq!(config.to_reading(&json!({"x": 1}), &meter), None);
+    }
+
+    #[test]
+    fn device_timestamp_and_quality() {
+        let config = StackConfiguration::default();
+        let source: SocketAddr = "192.168.86.40:8556".parse().unwrap();
+
+        let heartbeat = json!({
+            "ip_address": "192.168.86.40",
+            "mac_address": "80c955645cd4",
+            "name": "plug",
+            "status": "On",
+            "power": 5.0,
+            "voltage": 120.0,
+            "current": 0.04,
+            "timestamp": "04/10/2021_19:34:06.582,[00000000]",
+            "quality": "[0000000000000]"
+        });
+        let reading = config.to_reading(&heartbeat, &source).unwrap();
+        assert_eq!(
+            reading.timestamp.as_deref(),
+            Some("04/10/2021_19:34:06.582,[00000000]")
+        );
+        assert_eq!(reading.quality.as_deref(), Some("[0000000000000]"));
+
+        // Heartbeats without them fall back to the receive time and good quality
+        let heartbeat = serde_json::to_value(Data::default()).unwrap();
+        assert!(heartbeat.get("timestamp").is_none());
+        let reading = config.to_reading(&heartbeat, &source).unwrap();
+        assert_eq!(reading.timestamp, None);
+        assert_eq!(reading.quality, None);
+    }
+
+    #[test]
+    fn plug_heartbeat_tags() {
+        let data = Data {
+            mac_address: "80c955645cd4".to_string(),
+            power: 12.5,
+            frequency: Some(60.0),
+            ..Default::default()
+        };
+        let tags = data.tags();
+        assert_eq!(tags[0], Tag::new(OES_PLUG_STATUS, TagValue::Enum(0)));
+        assert_eq!(tags[0].quality_name(), "OES.Plug.Status.Quality");
+        assert_eq!(tags[0].timestamp_name(), "OES.Plug.Status.Timestamp");
+        assert_eq!(tags.len(), 5);
+
+        let reading = Reading {
+            mac_address: data.mac_address.clone(),
+            tags,
+            ..Default::default()
+        };
+        assert_eq!(reading.f64(OES_PLUG_POWER), Some(12.5));
+        assert_eq!(reading.f64("OES.Plug.Frequency"), Some(60.0));
+        assert_eq!(reading.f64(OES_PLUG_STATUS), None);
+    }
+
+    #[test]
+    fn unknown_status_is_invalid() {
+        let data = Data {
+            status: PlugStatus::Unknown,
+            ..Default::default()
+        };
+        let tags = data.tags();
+        assert_eq!(tags[0].value, TagValue::Enum(2));
+        assert!(tags[0].invalid);
+        assert!(tags[1..].iter().all(|t| !t.invalid));
+
+        let data = Data {
+            status: PlugStatus::On,
+            ..Default::default()
+        };
+        assert_eq!(data.tags()[0], Tag::new(OES_PLUG_STATUS, TagValue::Enum(1)));
+    }
+
+    #[test]
+    fn power_strip_outlets() {
+        use crate::{OESOutlet, Outlet};
+
+        let strip = OESPlug {
+            ip_address: "192.168.86.60".to_string(),
+            mac_address: "80c955640001".to_string(),
+            mrid: "strip".to_string(),
+            outlets: vec![
+                OESOutlet {
+                    index: 1,
+                    mrid: "outlet-1".to_string(),
+                },
+                OESOutlet {
+                    index: 2,
+                    mrid: "outlet-2".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+        let config = StackConfiguration {
+            uncontrollable_plugs: vec![strip.clone()],
+            controllable_plugs: vec![strip],
+            ..Default::default()
+        };
+
+        let data = Data {
+            mac_address: "80c955640001".to_string(),
+            status: PlugStatus::On,
+            power: 40.0,
+            voltage: 120.0,
+            frequency: Some(60.0),
+            outlets: vec![
+                Outlet {
+                    index: 1,
+                    status: PlugStatus::On,
+                    power: 40.0,
+                    current: 0.33,
+                },
+                Outlet {
+                    index: 2,
+                    status: PlugStatus::Unknown,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let buf = serde_json::to_vec(&data).unwrap();
+        let source = "192.168.86.60:8556".parse().unwrap();
+        let guard = Mutex::new(ReplayGuard::default());
+
+        let readings = config.decode_readings(&buf, &source, &guard).unwrap();
+        assert_eq!(readings.len(), 3);
+        assert_eq!(readings[0].outlet, None);
+        assert_eq!(readings[0].device_id(), "80c955640001");
+        assert_eq!(readings[1].device_id(), "80c955640001/1");
+        assert_eq!(readings[1].f64(OES_PLUG_POWER), Some(40.0));
+        assert_eq!(readings[1].f64(OES_PLUG_VOLTAGE), Some(120.0));
+        assert_eq!(readings[1].f64(OES_PLUG_FREQUENCY), Some(60.0));
+        assert!(readings[2].tags[0].invalid);
+
+        // Each outlet has its own mRID
+        let mrids: Vec<_> = readings
+            .iter()
+            .map(|r| config.lookup_mrid(&r.mac_address, r.outlet))
+            .collect();
+        assert_eq!(
+            mrids,
+            vec![
+                Some("strip".to_string()),
+                Some("outlet-1".to_string()),
+                Some("outlet-2".to_string())
+            ]
+        );
+        assert_eq!(config.lookup_mrid_for_outlet("80c955640001", 3), None);
+
+        let (plug, outlet) = config.lookup_controllable_target("outlet-2").unwrap();
+        assert_eq!(plug.mrid, "strip");
+        assert_eq!(outlet, Some(2));
+        assert_eq!(config.lookup_controllable_target("strip").unwrap().1, None);
+        assert!(config.lookup_controllable_target("other").is_none());
+    }
+}